@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=../proto/*");
+
+    tonic_build::configure()
+        .build_server(false)
+        .compile(&["../proto/core.proto"], &["../proto/"])?;
+    Ok(())
+}
@@ -0,0 +1,363 @@
+use clap::{Args, Subcommand, ValueEnum};
+use dialoguer::{theme::ColorfulTheme, Password};
+
+use crate::{
+    commands::connect,
+    config_management::Configuration,
+    output::{print_rows, print_success, Cell, OutputFormat},
+    relay::admin::{
+        DeleteUserRequest,
+        DisconnectRunnerRequest,
+        GenerateTokenRequest,
+        GenericResponse,
+        ListRunnersRequest,
+        ListTasksRequest,
+        ListTokensRequest,
+        ListUsersRequest,
+        RevokeTokenRequest,
+        TaskState,
+        TokenInfo,
+        TokenScope,
+    },
+};
+
+type Error = Box<dyn std::error::Error>;
+
+/// Options shared by every admin command.
+#[derive(Args, Debug)]
+pub(crate) struct AdminArgs {
+    #[clap(subcommand)]
+    command: AdminCommand,
+    /// The relay's gRPC URL. Defaults to the URL you logged in with.
+    #[clap(long, global = true)]
+    url:     Option<String>,
+    /// The relay's admin token. You will be prompted if omitted.
+    #[clap(long, env = "RELAY_ADMIN_TOKEN", hide_env_values = true, global = true)]
+    token:   Option<String>,
+    /// How to print results.
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table, global = true)]
+    output:  OutputFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Manage the users allowed to use the relay.
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Manage users' tokens.
+    #[clap(subcommand)]
+    Token(TokenCommand),
+    /// Inspect and disconnect connected runners.
+    #[clap(subcommand)]
+    Runner(RunnerCommand),
+    /// Inspect the commands currently running.
+    #[clap(subcommand)]
+    Task(TaskCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    /// List every user and their tokens.
+    List,
+    /// Create a user, issuing them a client token and a runner token.
+    Add {
+        zid: String,
+        /// How long the tokens are valid for, in seconds. They never expire if
+        /// omitted.
+        #[clap(long)]
+        ttl: Option<u64>,
+    },
+    /// Delete a user and all of their tokens.
+    Remove { zid: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommand {
+    /// Issue a user a new token, creating the user if they don't exist.
+    Issue {
+        zid:      String,
+        /// What the token may be used for.
+        #[clap(long, value_enum)]
+        scope:    Scope,
+        /// A label to tell the token apart from the user's others.
+        #[clap(long)]
+        label:    Option<String>,
+        /// How long the token is valid for, in seconds. It never expires if
+        /// omitted.
+        #[clap(long)]
+        ttl:      Option<u64>,
+        /// The id of a token to revoke once the new one is issued.
+        #[clap(long)]
+        replaces: Option<String>,
+    },
+    /// List a user's tokens.
+    List { zid: String },
+    /// Revoke one of a user's tokens.
+    Revoke { zid: String, id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum RunnerCommand {
+    /// List the connected runners.
+    List,
+    /// Disconnect a user's runner. It will reconnect unless its token is
+    /// revoked first.
+    Kick { zid: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum TaskCommand {
+    /// List the commands currently running.
+    List,
+}
+
+/// What a token may be used for.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Scope {
+    /// Running commands with this client.
+    Client,
+    /// Connecting a runner.
+    Runner,
+}
+
+impl From<Scope> for TokenScope {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::Client => TokenScope::Client,
+            Scope::Runner => TokenScope::Runner,
+        }
+    }
+}
+
+const TOKEN_COLUMNS: [&str; 6] = ["zid", "id", "label", "scope", "created", "expires"];
+const ISSUED_COLUMNS: [&str; 5] = ["zid", "id", "scope", "token", "expires"];
+
+/// Runs an admin command against the relay.
+pub(crate) async fn run(args: AdminArgs) -> Result<(), Error> {
+    let url = match args.url {
+        Some(url) => url,
+        None => match Configuration::load()? {
+            Some(config) => config.url,
+            None => return Err("no relay URL given; pass --url or run `client login <url>`".into()),
+        },
+    };
+    let token = match args.token {
+        Some(t) => t,
+        None => Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Please enter the relay's admin token")
+            .interact()?,
+    };
+
+    let mut client = connect(&Configuration { url, token }).await?;
+    let format = args.output;
+
+    match args.command {
+        AdminCommand::User(UserCommand::List) => {
+            let mut rows = Vec::new();
+            let mut page_token = String::new();
+            loop {
+                let page = client
+                    .list_users(ListUsersRequest {
+                        page_size: 0,
+                        page_token,
+                    })
+                    .await?
+                    .into_inner();
+
+                for user in page.users {
+                    if user.tokens.is_empty() {
+                        let mut row = vec![Cell::from(user.zid.as_str())];
+                        row.extend((1..TOKEN_COLUMNS.len()).map(|_| Cell::from("")));
+                        rows.push(row);
+                    }
+                    rows.extend(user.tokens.iter().map(|t| token_row(&user.zid, t)));
+                }
+
+                if page.next_page_token.is_empty() {
+                    break;
+                }
+                page_token = page.next_page_token;
+            }
+
+            print_rows(format, &TOKEN_COLUMNS, &rows);
+        },
+        AdminCommand::User(UserCommand::Add { zid, ttl }) => {
+            let mut rows = Vec::new();
+            for scope in [Scope::Client, Scope::Runner] {
+                let label = format!("{scope:?}").to_lowercase();
+                let issued = client
+                    .generate_token(GenerateTokenRequest {
+                        zid: zid.clone(),
+                        label,
+                        ttl_seconds: ttl.unwrap_or(0),
+                        replaces: String::new(),
+                        scope: TokenScope::from(scope) as i32,
+                    })
+                    .await?
+                    .into_inner();
+                rows.push(issued_row(
+                    &zid,
+                    issued.token,
+                    issued.info.unwrap_or_default(),
+                ));
+            }
+
+            print_rows(format, &ISSUED_COLUMNS, &rows);
+        },
+        AdminCommand::User(UserCommand::Remove { zid }) => {
+            let response = client
+                .delete_user(DeleteUserRequest { zid: zid.clone() })
+                .await?;
+            check(response.into_inner())?;
+            print_success(format, &format!("Removed {zid}"));
+        },
+        AdminCommand::Token(TokenCommand::Issue {
+            zid,
+            scope,
+            label,
+            ttl,
+            replaces,
+        }) => {
+            let issued = client
+                .generate_token(GenerateTokenRequest {
+                    zid:         zid.clone(),
+                    label:       label.unwrap_or_default(),
+                    ttl_seconds: ttl.unwrap_or(0),
+                    replaces:    replaces.unwrap_or_default(),
+                    scope:       TokenScope::from(scope) as i32,
+                })
+                .await?
+                .into_inner();
+
+            let row = issued_row(&zid, issued.token, issued.info.unwrap_or_default());
+            print_rows(format, &ISSUED_COLUMNS, &[row]);
+        },
+        AdminCommand::Token(TokenCommand::List { zid }) => {
+            let tokens = client
+                .list_tokens(ListTokensRequest { zid: zid.clone() })
+                .await?
+                .into_inner()
+                .tokens;
+
+            let rows = tokens
+                .iter()
+                .map(|t| token_row(&zid, t))
+                .collect::<Vec<_>>();
+            print_rows(format, &TOKEN_COLUMNS, &rows);
+        },
+        AdminCommand::Token(TokenCommand::Revoke { zid, id }) => {
+            let response = client
+                .revoke_token(RevokeTokenRequest {
+                    zid:      zid.clone(),
+                    token_id: id.clone(),
+                })
+                .await?;
+            check(response.into_inner())?;
+            print_success(format, &format!("Revoked {zid}'s token {id}"));
+        },
+        AdminCommand::Runner(RunnerCommand::List) => {
+            let runners = client
+                .list_runners(ListRunnersRequest {})
+                .await?
+                .into_inner()
+                .runners;
+
+            let rows = runners
+                .into_iter()
+                .map(|r| {
+                    vec![
+                        Cell::from(r.zid),
+                        Cell::from(r.address),
+                        Cell::from(r.version),
+                        Cell::Time(r.connected_at),
+                        Cell::Time(r.last_seen_at),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print_rows(
+                format,
+                &["zid", "address", "version", "connected", "last seen"],
+                &rows,
+            );
+        },
+        AdminCommand::Runner(RunnerCommand::Kick { zid }) => {
+            let response = client
+                .disconnect_runner(DisconnectRunnerRequest { zid: zid.clone() })
+                .await?;
+            check(response.into_inner())?;
+            print_success(format, &format!("Disconnected {zid}'s runner"));
+        },
+        AdminCommand::Task(TaskCommand::List) => {
+            let tasks = client
+                .list_tasks(ListTasksRequest {})
+                .await?
+                .into_inner()
+                .tasks;
+
+            let rows = tasks
+                .into_iter()
+                .map(|t| {
+                    let state = match TaskState::from_i32(t.state) {
+                        Some(TaskState::Running) => "running",
+                        Some(TaskState::Cancelling) => "cancelling",
+                        None => "unknown",
+                    };
+                    let command = std::iter::once(t.command)
+                        .chain(t.arguments)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    vec![
+                        Cell::from(t.id),
+                        Cell::from(t.zid),
+                        Cell::from(command),
+                        Cell::Time(t.started_at),
+                        Cell::from(state),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print_rows(format, &["id", "zid", "command", "started", "state"], &rows);
+        },
+    }
+
+    Ok(())
+}
+
+fn issued_row(zid: &str, token: String, info: TokenInfo) -> Vec<Cell> {
+    vec![
+        Cell::from(zid),
+        Cell::from(info.id),
+        Cell::from(scope_name(info.scope)),
+        Cell::from(token),
+        Cell::Time(info.expires_at),
+    ]
+}
+
+fn token_row(zid: &str, token: &TokenInfo) -> Vec<Cell> {
+    vec![
+        Cell::from(zid),
+        Cell::from(token.id.as_str()),
+        Cell::from(token.label.as_str()),
+        Cell::from(scope_name(token.scope)),
+        Cell::Time(token.created_at),
+        Cell::Time(token.expires_at),
+    ]
+}
+
+fn scope_name(scope: i32) -> &'static str {
+    match TokenScope::from_i32(scope) {
+        Some(TokenScope::Client) => "client",
+        Some(TokenScope::Runner) => "runner",
+        Some(TokenScope::Any) => "any",
+        Some(TokenScope::Unspecified) | None => "unknown",
+    }
+}
+
+/// Turns an unsuccessful [`GenericResponse`] into an error.
+fn check(response: GenericResponse) -> Result<(), Error> {
+    if response.success {
+        Ok(())
+    } else {
+        Err(response.error.into())
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{IsTerminal, Write};
+
+use colored::Colorize;
+use dialoguer::{theme::ColorfulTheme, Password};
+use tonic::{
+    metadata::MetadataValue,
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{Channel, ClientTlsConfig},
+    Request,
+    Status,
+};
+
+use crate::{
+    config_management::{config_path, Configuration},
+    relay::core::{
+        command_output::Data,
+        relay_service_client::RelayServiceClient,
+        CancelCommandRequest,
+        CommandRequest,
+        Directory,
+        OutputStream,
+        TaskStatus,
+    },
+};
+
+type Error = Box<dyn std::error::Error>;
+
+/// Attaches the user's bearer token to every outgoing request.
+#[derive(Debug, Clone)]
+pub(crate) struct BearerAuth {
+    header: MetadataValue<tonic::metadata::Ascii>,
+}
+
+impl Interceptor for BearerAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request
+            .metadata_mut()
+            .insert("authorization", self.header.clone());
+        Ok(request)
+    }
+}
+
+/// Stores the relay URL and token for future commands.
+pub(crate) fn login(url: String, token: Option<String>) -> Result<(), Error> {
+    let token = match token {
+        Some(t) => t,
+        None => Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Please enter your token")
+            .interact()?,
+    };
+
+    Configuration { url, token }.save()?;
+    println!(
+        "{} {}",
+        "✔ Saved relay credentials to".green(),
+        config_path().display()
+    );
+
+    Ok(())
+}
+
+/// Removes any stored relay credentials.
+pub(crate) fn logout() -> Result<(), Error> {
+    Configuration::remove()?;
+    println!("{}", "✔ Logged out".green());
+    Ok(())
+}
+
+/// Connects to the relay using the stored configuration.
+pub(crate) async fn connect(
+    config: &Configuration,
+) -> Result<RelayServiceClient<InterceptedService<Channel, BearerAuth>>, Error> {
+    let mut endpoint = Channel::from_shared(config.url.clone())?;
+    if config.url.starts_with("https://") {
+        endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+    }
+
+    let channel = endpoint.connect().await?;
+    let auth = BearerAuth {
+        header: format!("Bearer {}", config.token).parse()?,
+    };
+
+    Ok(RelayServiceClient::with_interceptor(channel, auth)
+        .send_gzip()
+        .accept_gzip())
+}
+
+/// The exit code used when a command is killed for exceeding its timeout,
+/// matching `timeout(1)`.
+const TIMED_OUT_EXIT_CODE: i32 = 124;
+
+/// The exit code used when a command is cancelled, matching a shell's exit code
+/// for `SIGINT`.
+const CANCELLED_EXIT_CODE: i32 = 130;
+
+/// The exit code used when the runner's policy doesn't allow a command,
+/// matching a shell's exit code for a command that can't be executed.
+const REJECTED_EXIT_CODE: i32 = 126;
+
+/// Uploads the current directory, runs `command` on VLab and returns its exit
+/// code.
+pub(crate) async fn run(
+    command: &str,
+    arguments: Vec<String>,
+    timeout: Option<u32>,
+) -> Result<i32, Error> {
+    let Some(config) = Configuration::load()? else {
+        return Err("you are not logged in; run `client login <url>` first".into());
+    };
+
+    let directory = Directory::collect(&std::env::current_dir()?)?;
+    let mut client = connect(&config).await?;
+
+    let mut output = client
+        .command_stream(CommandRequest {
+            command: command.to_string(),
+            arguments,
+            directory: Some(directory),
+            timeout_seconds: timeout.unwrap_or(0),
+        })
+        .await?
+        .into_inner();
+
+    // print output as it arrives, until the final result comes through. the
+    // first Ctrl-C cancels the task, and the second stops waiting for it
+    let mut task_id = None;
+    let mut cancelling = false;
+    loop {
+        let frame = tokio::select! {
+            frame = output.message() => frame?,
+            _ = tokio::signal::ctrl_c() => {
+                let Some(id) = task_id.clone().filter(|_| !cancelling) else {
+                    return Ok(CANCELLED_EXIT_CODE);
+                };
+
+                eprintln!("{}", "Cancelling the command...".yellow());
+                cancelling = true;
+                client
+                    .cancel_command(CancelCommandRequest { task_id: id })
+                    .await?;
+                continue;
+            },
+        };
+
+        match frame.and_then(|f| f.data) {
+            Some(Data::TaskId(id)) => task_id = Some(id),
+            Some(Data::Chunk(chunk)) => {
+                if chunk.stream == OutputStream::Stderr as i32 {
+                    write_stderr(&chunk.data)?;
+                } else {
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&chunk.data)?;
+                    stdout.flush()?;
+                }
+            },
+            Some(Data::Result(response)) => {
+                print!("{}", response.stdout);
+                write_stderr(response.stderr.as_bytes())?;
+
+                if response.status == TaskStatus::TimedOut as i32 {
+                    eprintln!("{}", "❌ The command timed out and was killed".red());
+                    return Ok(TIMED_OUT_EXIT_CODE);
+                }
+                if response.status == TaskStatus::Cancelled as i32 {
+                    eprintln!("{}", "❌ The command was cancelled".red());
+                    return Ok(CANCELLED_EXIT_CODE);
+                }
+                if response.status == TaskStatus::Rejected as i32 {
+                    return Ok(REJECTED_EXIT_CODE);
+                }
+                return Ok(i32::try_from(response.exit_code).unwrap_or(1));
+            },
+            None => break,
+        }
+    }
+
+    Err("the relay closed the stream before the command finished".into())
+}
+
+/// Writes command output to stderr, coloured red if stderr is a terminal.
+fn write_stderr(data: &[u8]) -> Result<(), std::io::Error> {
+    let mut stderr = std::io::stderr();
+    if stderr.is_terminal() {
+        write!(stderr, "{}", String::from_utf8_lossy(data).red())?;
+    } else {
+        stderr.write_all(data)?;
+    }
+
+    stderr.flush()
+}
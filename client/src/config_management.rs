@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use common::config::{config_dir, write_private};
+use serde::{Deserialize, Serialize};
+
+/// The persisted client configuration, created by `client login`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Configuration {
+    /// The URL of the relay's gRPC endpoint, e.g. `https://vlab-relay.example.com`.
+    pub(crate) url:   String,
+    /// The bearer token used to authenticate with the relay.
+    pub(crate) token: String,
+}
+
+/// Returns the path of the client's configuration file.
+pub(crate) fn config_path() -> PathBuf { config_dir().join("client.toml") }
+
+impl Configuration {
+    /// Loads the configuration from disk, if the user has logged in before.
+    pub(crate) fn load() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        Ok(Some(toml::from_str(&contents)?))
+    }
+
+    /// Writes the configuration to disk, readable only by the current user.
+    pub(crate) fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        write_private(&config_path(), toml::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Removes the configuration from disk.
+    pub(crate) fn remove() -> Result<(), std::io::Error> {
+        let path = config_path();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
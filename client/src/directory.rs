@@ -0,0 +1,196 @@
+use std::path::Path;
+
+use colored::Colorize;
+use common::symlinks::{escaping_links, Tree};
+
+use crate::relay::core::{Directory, File};
+
+/// Directory names that are never uploaded to the relay.
+const IGNORED_DIRECTORIES: [&str; 1] = [".git"];
+
+impl Directory {
+    /// Walks `path` and collects every file and sub-directory in it, along
+    /// with their permissions.
+    ///
+    /// Symlinks are not followed, but collected as links. Links the runner
+    /// would refuse, because they point outside the directory, are skipped
+    /// with a warning, as are links whose targets aren't valid UTF-8.
+    pub(crate) fn collect(path: &Path) -> Result<Self, std::io::Error> {
+        let mut root = Self::collect_entries(path)?;
+
+        // every link is checked before any are removed, since removing a link
+        // can change how the others resolve
+        for link in escaping_links(&root) {
+            warn_skipped(&link, "it points outside the directory");
+            root.remove_link(&link);
+        }
+
+        Ok(root)
+    }
+
+    fn collect_entries(path: &Path) -> Result<Self, std::io::Error> {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let entry_name = entry.file_name().to_string_lossy().to_string();
+
+            if file_type.is_dir() {
+                if IGNORED_DIRECTORIES.contains(&entry_name.as_str()) {
+                    continue;
+                }
+                directories.push(Self::collect_entries(&entry.path())?);
+            } else if file_type.is_file() {
+                files.push(File {
+                    file_name: entry_name,
+                    data: std::fs::read(entry.path())?,
+                    mode: mode(&entry.metadata()?),
+                    ..File::default()
+                });
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                let Some(target) = target.to_str() else {
+                    warn_skipped(&entry.path(), "its target isn't valid UTF-8");
+                    continue;
+                };
+                files.push(File {
+                    file_name: entry_name,
+                    symlink_target: target.to_string(),
+                    ..File::default()
+                });
+            }
+        }
+
+        Ok(Self {
+            name,
+            files,
+            directories,
+        })
+    }
+
+    /// Removes the link at `path`, relative to this directory.
+    fn remove_link(&mut self, path: &Path) {
+        let mut dir = self;
+        let mut components = path.iter().peekable();
+        while let Some(name) = components.next() {
+            if components.peek().is_none() {
+                dir.files.retain(|f| f.file_name.as_str() != name);
+                return;
+            }
+
+            match dir.directories.iter_mut().find(|d| d.name.as_str() == name) {
+                Some(child) => dir = child,
+                None => return,
+            }
+        }
+    }
+}
+
+impl Tree for Directory {
+    fn name(&self) -> &str { &self.name }
+
+    fn directories(&self) -> &[Self] { &self.directories }
+
+    fn symlinks(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.files
+            .iter()
+            .filter(|f| !f.symlink_target.is_empty())
+            .map(|f| (f.file_name.as_str(), f.symlink_target.as_str()))
+    }
+}
+
+fn warn_skipped(path: &Path, reason: &str) {
+    eprintln!(
+        "{}",
+        format!("⚠ Skipping the symlink {}: {}", path.display(), reason).yellow()
+    );
+}
+
+/// Gets a file's Unix permission bits, or 0 where there are none.
+fn mode(metadata: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        os::unix::{ffi::OsStrExt, fs::symlink},
+    };
+
+    use crate::relay::core::Directory;
+
+    fn names(dir: &Directory) -> Vec<&str> {
+        let mut names = dir
+            .files
+            .iter()
+            .map(|f| f.file_name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn collects_files_directories_and_links() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("lab01");
+        std::fs::create_dir_all(path.join("sub")).unwrap();
+        std::fs::create_dir_all(path.join(".git")).unwrap();
+        std::fs::write(path.join("a.c"), "int main;").unwrap();
+        std::fs::write(path.join(".git/HEAD"), "").unwrap();
+        symlink("a.c", path.join("link.c")).unwrap();
+        symlink("../a.c", path.join("sub/up.c")).unwrap();
+
+        let dir = Directory::collect(&path).unwrap();
+
+        assert_eq!(dir.name, "lab01");
+        assert_eq!(names(&dir), ["a.c", "link.c"]);
+        let a = dir.files.iter().find(|f| f.file_name == "a.c").unwrap();
+        assert_eq!(a.data, b"int main;");
+        assert!(a.symlink_target.is_empty());
+        let link = dir.files.iter().find(|f| f.file_name == "link.c").unwrap();
+        assert_eq!(link.symlink_target, "a.c");
+
+        // `.git` is never uploaded
+        assert_eq!(dir.directories.len(), 1);
+        assert_eq!(dir.directories[0].name, "sub");
+        assert_eq!(names(&dir.directories[0]), ["up.c"]);
+    }
+
+    #[test]
+    fn skips_links_the_runner_would_refuse() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("lab01");
+        std::fs::create_dir_all(path.join("sub")).unwrap();
+        std::fs::write(path.join("a.c"), "").unwrap();
+        symlink("a.c", path.join("ok.c")).unwrap();
+        symlink("/etc/passwd", path.join("absolute")).unwrap();
+        symlink("../secret", path.join("outside")).unwrap();
+        symlink("../../secret", path.join("sub/outside")).unwrap();
+        symlink(".", path.join("here")).unwrap();
+        symlink("here/..", path.join("through")).unwrap();
+        symlink(OsStr::from_bytes(b"\xff.c"), path.join("invalid")).unwrap();
+
+        let dir = Directory::collect(&path).unwrap();
+
+        assert_eq!(names(&dir), ["a.c", "here", "ok.c"]);
+        assert!(names(&dir.directories[0]).is_empty());
+    }
+}
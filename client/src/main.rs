@@ -1 +1,74 @@
-fn main() {}
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+use human_panic::setup_panic;
+
+mod admin;
+mod commands;
+mod config_management;
+mod directory;
+mod output;
+mod relay;
+
+/// A VLab relay client. Run `autotest` and `give` against the files in your
+/// current directory, executed on your VLab account through the relay.
+#[derive(Parser, Debug)]
+#[clap(name = "vlab relay client", author, version, about, long_about = None, verbatim_doc_comment)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+    /// The number of seconds a command may run for before it is killed. The
+    /// relay's maximum is used if omitted.
+    #[clap(long, global = true)]
+    timeout: Option<u32>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Store the relay's URL and your token for future commands.
+    Login {
+        /// The relay's gRPC URL, e.g. `https://vlab-relay.example.com`.
+        url:   String,
+        /// The token to authenticate with. You will be prompted if omitted.
+        #[clap(long)]
+        token: Option<String>,
+    },
+    /// Remove the stored relay credentials.
+    Logout,
+    /// Run `autotest` on VLab against the current directory.
+    Autotest {
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        arguments: Vec<String>,
+    },
+    /// Run `give` on VLab against the current directory.
+    Give {
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        arguments: Vec<String>,
+    },
+    /// Manage the relay's users, tokens, runners and tasks. Requires the
+    /// relay's admin token.
+    Admin(admin::AdminArgs),
+}
+
+// `setup_panic!` expands to the deprecated `PanicInfo` alias
+#[allow(deprecated)]
+#[tokio::main]
+async fn main() {
+    setup_panic!();
+    let args = Args::parse();
+
+    let result = match args.command {
+        Command::Login { url, token } => commands::login(url, token).map(|_| 0),
+        Command::Logout => commands::logout().map(|_| 0),
+        Command::Autotest { arguments } => commands::run("autotest", arguments, args.timeout).await,
+        Command::Give { arguments } => commands::run("give", arguments, args.timeout).await,
+        Command::Admin(admin_args) => admin::run(admin_args).await.map(|()| 0),
+    };
+
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e.to_string().red());
+            std::process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use colored::Colorize;
+use serde_json::{Map, Value};
+
+/// How results are printed.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Aligned columns, for people.
+    Table,
+    /// A JSON array of objects, for scripts.
+    Json,
+}
+
+/// A single value in a row of results.
+pub(crate) enum Cell {
+    Text(String),
+    /// Seconds since the unix epoch, or 0 for "never".
+    Time(i64),
+}
+
+impl Cell {
+    fn to_text(&self) -> String {
+        match self {
+            Cell::Text(text) => text.clone(),
+            Cell::Time(0) => "never".to_string(),
+            Cell::Time(seconds) => match DateTime::from_timestamp(*seconds, 0) {
+                Some(time) => time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                None => seconds.to_string(),
+            },
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Cell::Text(text) => Value::String(text.clone()),
+            Cell::Time(0) => Value::Null,
+            Cell::Time(seconds) => Value::from(*seconds),
+        }
+    }
+}
+
+impl From<String> for Cell {
+    fn from(text: String) -> Self { Cell::Text(text) }
+}
+
+impl From<&str> for Cell {
+    fn from(text: &str) -> Self { Cell::Text(text.to_string()) }
+}
+
+/// Prints rows of results, each with one cell per column.
+pub(crate) fn print_rows(format: OutputFormat, columns: &[&str], rows: &[Vec<Cell>]) {
+    match format {
+        OutputFormat::Table => print_table(columns, rows),
+        OutputFormat::Json => {
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    let object = columns
+                        .iter()
+                        .zip(row)
+                        .map(|(column, cell)| (column.to_string(), cell.to_json()))
+                        .collect::<Map<_, _>>();
+                    Value::Object(object)
+                })
+                .collect::<Vec<_>>();
+            println!("{}", Value::Array(rows));
+        },
+    }
+}
+
+/// Prints the outcome of an action that doesn't produce any results.
+pub(crate) fn print_success(format: OutputFormat, message: &str) {
+    match format {
+        OutputFormat::Table => println!("{} {}", "✔".green(), message.green()),
+        OutputFormat::Json => println!("{}", serde_json::json!({ "success": true })),
+    }
+}
+
+fn print_table(columns: &[&str], rows: &[Vec<Cell>]) {
+    if rows.is_empty() {
+        println!("{}", "Nothing to show".dimmed());
+        return;
+    }
+
+    let rows = rows
+        .iter()
+        .map(|row| row.iter().map(Cell::to_text).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(std::iter::once(column.len()))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    let header = columns
+        .iter()
+        .zip(&widths)
+        .map(|(column, width)| format!("{:width$}", column.to_uppercase()))
+        .collect::<Vec<_>>();
+    println!("{}", header.join("  ").trim_end().bold());
+
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(text, width)| format!("{text:width$}"))
+            .collect::<Vec<_>>();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
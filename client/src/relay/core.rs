@@ -0,0 +1,2 @@
+#![allow(clippy::pedantic, dead_code)]
+tonic::include_proto!("core");
@@ -0,0 +1,2 @@
+pub(crate) mod admin;
+pub(crate) mod core;
@@ -0,0 +1,79 @@
+use std::{
+    fs::{DirBuilder, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Returns the directory holding the relay's configuration files:
+/// `vlab-relay` in `$XDG_CONFIG_HOME`, or in `~/.config` if it isn't set.
+pub fn config_dir() -> PathBuf {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&std::env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+
+    base.join("vlab-relay")
+}
+
+/// Writes `contents` to the file at `path`, creating it and its directory if
+/// they don't exist. Both are only accessible by the current user, since
+/// config files hold tokens.
+///
+/// The file is created private, rather than made private after it has been
+/// written to, and files that already exist, e.g. ones written before this
+/// was done, are made private before they're overwritten.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(parent)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    file.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    use super::write_private;
+
+    fn mode(path: &Path) -> u32 { std::fs::metadata(path).unwrap().permissions().mode() & 0o777 }
+
+    #[test]
+    fn writes_private_files_in_private_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vlab-relay/client.toml");
+
+        write_private(&path, b"token = \"secret\"").unwrap();
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"token = \"secret\"");
+    }
+
+    #[test]
+    fn makes_existing_files_private_and_replaces_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("runner.toml");
+        std::fs::write(&path, "a much longer old config file").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"new").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+}
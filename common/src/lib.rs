@@ -0,0 +1,4 @@
+//! Code shared by the client and the runner.
+
+pub mod config;
+pub mod symlinks;
@@ -0,0 +1,166 @@
+//! Checks that the symlinks in an uploaded directory point inside it.
+//!
+//! The runner refuses uploads with links that don't, and the client skips
+//! them rather than sending them, so both make the decision here.
+
+use std::path::{Component, Path, PathBuf};
+
+/// A directory being uploaded, as far as its symlinks are concerned.
+pub trait Tree: Sized {
+    fn name(&self) -> &str;
+
+    fn directories(&self) -> &[Self];
+
+    /// The names and targets of the symlinks directly inside this directory.
+    fn symlinks(&self) -> impl Iterator<Item = (&str, &str)>;
+}
+
+/// Checks whether a symlink at `parents` inside `root`, pointing at `target`,
+/// resolves to somewhere inside `root`.
+///
+/// The target is resolved without touching the disk, so it must not pass
+/// through other symlinks, whose targets could change what `..` means.
+pub fn contains_target<T: Tree>(root: &T, parents: &[&str], target: &str) -> bool {
+    let target = Path::new(target);
+    if target.has_root() {
+        return false;
+    }
+
+    let mut resolved = parents.to_vec();
+    let mut components = target.components().peekable();
+    while let Some(component) = components.next() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if resolved.pop().is_none() {
+                    return false;
+                }
+            },
+            Component::Normal(name) => {
+                let Some(name) = name.to_str() else {
+                    return false;
+                };
+                resolved.push(name);
+                if components.peek().is_some() && is_symlink(root, &resolved) {
+                    return false;
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Returns the path of every symlink in `root` that points outside it,
+/// relative to `root`.
+pub fn escaping_links<T: Tree>(root: &T) -> Vec<PathBuf> {
+    let mut escaping = Vec::new();
+    find_escaping_links(root, root, &[], &mut escaping);
+    escaping
+}
+
+fn find_escaping_links<T: Tree>(root: &T, dir: &T, parents: &[&str], escaping: &mut Vec<PathBuf>) {
+    for (name, target) in dir.symlinks() {
+        if !contains_target(root, parents, target) {
+            escaping.push(parents.iter().chain([&name]).collect());
+        }
+    }
+
+    for child in dir.directories() {
+        let parents = [parents, &[child.name()]].concat();
+        find_escaping_links(root, child, &parents, escaping);
+    }
+}
+
+/// Whether the entry at `path` inside `root` is a symlink.
+fn is_symlink<T: Tree>(root: &T, path: &[&str]) -> bool {
+    let Some((name, parents)) = path.split_last() else {
+        return false;
+    };
+
+    let mut dir = root;
+    for parent in parents {
+        match dir.directories().iter().find(|d| d.name() == *parent) {
+            Some(child) => dir = child,
+            None => return false,
+        }
+    }
+
+    dir.symlinks().any(|(link, _)| link == *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{contains_target, escaping_links, Tree};
+
+    struct Dir {
+        name:        &'static str,
+        links:       Vec<(&'static str, &'static str)>,
+        directories: Vec<Dir>,
+    }
+
+    impl Tree for Dir {
+        fn name(&self) -> &str { self.name }
+
+        fn directories(&self) -> &[Self] { &self.directories }
+
+        fn symlinks(&self) -> impl Iterator<Item = (&str, &str)> { self.links.iter().copied() }
+    }
+
+    fn dir(
+        name: &'static str,
+        links: Vec<(&'static str, &'static str)>,
+        directories: Vec<Dir>,
+    ) -> Dir {
+        Dir {
+            name,
+            links,
+            directories,
+        }
+    }
+
+    #[test]
+    fn targets_must_resolve_inside_the_root() {
+        let root = dir("task", vec![], vec![dir("sub", vec![], vec![])]);
+
+        for target in ["a.c", "./a.c", "sub/a.c", "sub/../a.c", "."] {
+            assert!(contains_target(&root, &[], target), "{target}");
+        }
+        assert!(contains_target(&root, &["sub"], "../a.c"));
+
+        for target in ["/etc/passwd", "..", "../task/a.c", "sub/../../a.c"] {
+            assert!(!contains_target(&root, &[], target), "{target}");
+        }
+        assert!(!contains_target(&root, &["sub"], "../../a.c"));
+    }
+
+    #[test]
+    fn finds_every_escaping_link() {
+        let root = dir(
+            "task",
+            vec![
+                ("ok", "a.c"),
+                ("here", "."),
+                ("through", "here/.."),
+                ("up", ".."),
+            ],
+            vec![dir(
+                "sub",
+                vec![("ok", "../a.c"), ("out", "../../a.c")],
+                vec![],
+            )],
+        );
+
+        assert_eq!(
+            escaping_links(&root),
+            [
+                PathBuf::from("through"),
+                PathBuf::from("up"),
+                PathBuf::from("sub/out")
+            ]
+        );
+    }
+}
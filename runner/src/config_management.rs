@@ -1,12 +1,37 @@
+use std::{io::IsTerminal, path::PathBuf};
+
+use colored::Colorize;
+use common::config::{config_dir, write_private};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password};
+use serde::{Deserialize, Serialize};
+
+type Error = Box<dyn std::error::Error>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Configuration {
     pub(crate) host:   String,
     pub(crate) secure: bool,
     pub(crate) token:  String,
 }
 
+/// Settings given as flags or environment variables, which take precedence
+/// over the config file.
+#[derive(clap::Args, Debug)]
+pub(crate) struct ConfigArgs {
+    /// The relay's hostname, e.g. `vlab-relay.example.com`.
+    #[clap(long, env = "RELAY_HOST")]
+    host:        Option<String>,
+    /// Whether to connect securely (wss://).
+    #[clap(long, env = "RELAY_SECURE")]
+    secure:      Option<bool>,
+    /// The token to authenticate with.
+    #[clap(long, env = "RELAY_TOKEN", hide_env_values = true)]
+    token:       Option<String>,
+    /// Ask for every setting again, and save the answers to the config file.
+    #[clap(long)]
+    reconfigure: bool,
+}
+
 impl Configuration {
     pub(crate) fn get_url(&self) -> String {
         if self.secure {
@@ -15,30 +40,111 @@ impl Configuration {
             format!("ws://{}", self.host)
         }
     }
+
+    /// Loads the configuration from disk, if it has been saved before.
+    fn load() -> Result<Option<Self>, Error> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(&path)?;
+        let config =
+            toml::from_str(&contents).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        Ok(Some(config))
+    }
+
+    /// Writes the configuration to disk, readable only by the current user.
+    fn save(&self) -> Result<(), Error> {
+        write_private(&config_path(), toml::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
 }
 
-pub(crate) fn get_config() -> Configuration {
-    // collect values
+/// Returns the path of the runner's configuration file.
+pub(crate) fn config_path() -> PathBuf { config_dir().join("runner.toml") }
+
+/// Works out the configuration from flags, environment variables and the
+/// config file, in decreasing order of precedence.
+///
+/// Anything still missing is prompted for, and the answers are saved to the
+/// config file for next time. Prompting is impossible without a terminal, so
+/// missing settings are an error then.
+pub(crate) fn get_config(args: ConfigArgs) -> Result<Configuration, Error> {
+    let stored = match Configuration::load() {
+        Ok(stored) => stored,
+        // reconfiguring replaces a broken config file
+        Err(_) if args.reconfigure => None,
+        Err(e) => return Err(e),
+    };
+
+    let host = args
+        .host
+        .or_else(|| stored.as_ref().map(|c| c.host.clone()));
+    let secure = args.secure.or(stored.as_ref().map(|c| c.secure));
+    let stored_token = stored.map(|c| c.token);
+
+    if !args.reconfigure {
+        if let (Some(host), Some(secure), Some(token)) =
+            (&host, secure, args.token.as_ref().or(stored_token.as_ref()))
+        {
+            return Ok(Configuration {
+                host: host.clone(),
+                secure,
+                token: token.clone(),
+            });
+        }
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return Err(format!(
+            "the runner isn't configured; pass --host, --secure and --token (or RELAY_HOST, \
+             RELAY_SECURE and RELAY_TOKEN), or run it in a terminal once to save {}",
+            config_path().display()
+        )
+        .into());
+    }
+
+    // collect values, suggesting whatever was already set
     let host = Input::<String>::with_theme(&ColorfulTheme::default())
         .with_prompt("Please your relay's hostname")
-        .default("vlab-relay.example.com".into())
-        .interact_text()
-        .unwrap();
+        .default(host.unwrap_or_else(|| "vlab-relay.example.com".into()))
+        .interact_text()?;
     let secure = Confirm::with_theme(&ColorfulTheme::default())
         .with_prompt("Use a secure connection (wss://)?")
-        .default(true)
-        .interact()
-        .unwrap();
-    let token = Password::with_theme(&ColorfulTheme::default())
-        .with_prompt("Please enter your token")
-        .interact()
-        .unwrap();
+        .default(secure.unwrap_or(true))
+        .interact()?;
+    let token = match args.token {
+        Some(token) => token,
+        None => {
+            let prompt = if stored_token.is_some() {
+                "Please enter your token (leave empty to keep the current one)"
+            } else {
+                "Please enter your token"
+            };
+            let token = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt(prompt)
+                .allow_empty_password(stored_token.is_some())
+                .interact()?;
 
-    println!();
+            match stored_token {
+                Some(stored) if token.is_empty() => stored,
+                _ => token,
+            }
+        },
+    };
 
-    Configuration {
+    let config = Configuration {
         host,
         secure,
         token,
-    }
+    };
+    config.save()?;
+    println!(
+        "{} {}\n",
+        "✔ Saved the configuration to".green(),
+        config_path().display()
+    );
+
+    Ok(config)
 }
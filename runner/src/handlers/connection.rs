@@ -1,61 +1,292 @@
-use std::time::Duration;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use colored::Colorize;
-use futures::{channel::mpsc, future, pin_mut, StreamExt, TryStreamExt};
-use log::{error, warn};
+use futures::{channel::mpsc, future, pin_mut, SinkExt, Stream, StreamExt, TryStreamExt};
+use log::{error, info, warn};
 use spinners::Spinner;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokio::{net::TcpStream, time::MissedTickBehavior};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message},
+    MaybeTlsStream,
+    WebSocketStream,
+};
 
 use crate::{
     handlers::message::handle_message,
-    relay::ws_extensions::{socket_frame::Data, InitFrame, SocketFrame},
+    managers::tasks::{RunningTasks, TaskSettings},
+    relay::ws_extensions::{socket_frame::Data, InitAck, InitFrame, SocketFrame},
 };
 
+/// How often the relay is pinged.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How many heartbeats in a row the relay may miss before the connection is
+/// considered dead.
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// How long to wait for the relay to accept or reject the login.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The version of the websocket protocol this runner speaks.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// The optional protocol features this runner supports.
+const FEATURES: [&str; 3] = ["heartbeat", "output-streaming", "task-cancel"];
+
+/// How a connection to the relay ended.
+pub(crate) enum Disconnect {
+    /// The connection was closed or dropped, and is worth retrying.
+    Lost,
+    /// The relay refused the runner's credentials.
+    Rejected { reason: String },
+}
+
+/// How the relay answered the runner's login.
+enum Login {
+    Accepted(InitAck),
+    Rejected {
+        reason: String,
+    },
+    /// Relays from before logins were acknowledged just start sending requests,
+    /// so this holds the first message received instead of an ack, if any.
+    Unacknowledged(Option<Message>),
+    Lost,
+}
+
+/// Logs in to the relay and handles its requests until the connection ends.
 pub(crate) async fn handle_connection(
     spinner: Spinner,
     stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
     token: String,
-) {
+    settings: Arc<TaskSettings>,
+) -> Disconnect {
     spinner.stop_with_message("✔ Connected to relay \n".green().to_string());
 
-    // create proxy channel to relay messages
-    let (tx, rx) = mpsc::unbounded();
-    let (write, read) = stream.split();
-    let write = rx.map(Ok).forward(write);
+    let (mut write, mut read) = stream.split();
 
     // login to the relay
     let frame = SocketFrame {
         data: Some(Data::Init(InitFrame {
             zid: whoami::username(),
             token,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(ToString::to_string).collect(),
+            protocol_version: PROTOCOL_VERSION,
         })),
     };
 
     let vec_to_send = prost::Message::encode_to_vec(&frame);
     // send the login frame
-    if let Err(e) = tx.unbounded_send(Message::Binary(vec_to_send)) {
+    if let Err(e) = write.send(Message::Binary(vec_to_send)).await {
         error!("failed to send login frame: {}", e);
-        warn!("disconnected from relay; will attempt to reconnect in 5 seconds");
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        return;
+        return Disconnect::Lost;
     }
 
+    // wait to find out whether the login worked
+    let first_message = match wait_for_ack(&mut read).await {
+        Login::Accepted(ack) => {
+            println!("{}", format!("✔ Authenticated as {}\n", ack.zid).green());
+            info!(
+                "relay version {} (protocol version {}); features: {}",
+                ack.server_version,
+                ack.protocol_version,
+                ack.features.join(", ")
+            );
+            if !ack.warning.is_empty() {
+                warn!("{}", ack.warning);
+            }
+            None
+        },
+        Login::Rejected { reason } => return Disconnect::Rejected { reason },
+        Login::Unacknowledged(first_message) => {
+            warn!("the relay didn't acknowledge the login; it may be out of date");
+            first_message
+        },
+        Login::Lost => return Disconnect::Lost,
+    };
+
+    // create proxy channel to relay messages
+    let (tx, rx) = mpsc::unbounded();
+    let write = rx.map(Ok).forward(write);
+
     // execute closure for each message received
-    let messages = read.try_for_each(|msg| {
+    let running = RunningTasks::default();
+    let last_seen = Mutex::new(Instant::now());
+    let handle = |msg| {
         let tx = tx.clone();
+        let running = running.clone();
+        let settings = settings.clone();
 
         tokio::spawn(async move {
             // determine the type of msg received
-            handle_message(msg, tx).await;
+            handle_message(msg, tx, running, &settings).await;
         });
+    };
 
+    if let Some(msg) = first_message {
+        handle(msg);
+    }
+    let messages = read.try_for_each(|msg| {
+        *last_seen.lock().unwrap() = Instant::now();
+        handle(msg);
         future::ok(())
     });
 
-    pin_mut!(write, messages);
-    future::select(write, messages).await;
+    // a connection that died without being closed would otherwise go unnoticed
+    let heartbeat = heartbeat(&tx, &last_seen);
+
+    pin_mut!(write, messages, heartbeat);
+    future::select(future::select(write, messages), heartbeat).await;
+
+    Disconnect::Lost
+}
+
+/// Waits for the relay to acknowledge the login.
+async fn wait_for_ack<S>(read: &mut S) -> Login
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let wait = async {
+        while let Some(msg) = read.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("failed to read from relay: {}", e);
+                    return Login::Lost;
+                },
+            };
 
-    warn!("disconnected from relay; will attempt to reconnect in 5 seconds");
-    tokio::time::sleep(Duration::from_secs(5)).await;
+            match &msg {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                // relays that reject a login say so with an ack before closing
+                // the connection, so a close on its own (e.g. because the login
+                // took too long) is worth retrying
+                Message::Close(_) => return Login::Lost,
+                Message::Binary(data) => {
+                    if let Ok(SocketFrame {
+                        data: Some(Data::InitAck(ack)),
+                    }) = <SocketFrame as prost::Message>::decode(data.as_slice())
+                    {
+                        if ack.success {
+                            return Login::Accepted(ack);
+                        }
+                        return Login::Rejected { reason: ack.error };
+                    }
+                },
+                _ => {},
+            }
+
+            return Login::Unacknowledged(Some(msg));
+        }
+
+        Login::Lost
+    };
+
+    tokio::time::timeout(ACK_TIMEOUT, wait)
+        .await
+        .unwrap_or(Login::Unacknowledged(None))
+}
+
+/// Pings the relay every [`HEARTBEAT_INTERVAL`], returning once nothing has
+/// been received from it for [`MAX_MISSED_HEARTBEATS`] intervals.
+async fn heartbeat(tx: &mpsc::UnboundedSender<Message>, last_seen: &Mutex<Instant>) {
+    let mut ticks = tokio::time::interval(HEARTBEAT_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        if last_seen.lock().unwrap().elapsed() >= HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS {
+            warn!(
+                "relay missed {} heartbeats; dropping the connection",
+                MAX_MISSED_HEARTBEATS
+            );
+            return;
+        }
+
+        if tx.unbounded_send(Message::Ping(Vec::new())).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use prost::Message as _;
+    use tokio_tungstenite::tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    };
+
+    use super::{wait_for_ack, Login};
+    use crate::relay::ws_extensions::{socket_frame::Data, InitAck, SocketFrame};
+
+    async fn login(messages: Vec<Message>) -> Login {
+        wait_for_ack(&mut stream::iter(messages.into_iter().map(Ok))).await
+    }
+
+    fn close(code: CloseCode, reason: &str) -> Message {
+        Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        }))
+    }
+
+    fn ack(success: bool, error: &str) -> Message {
+        let frame = SocketFrame {
+            data: Some(Data::InitAck(InitAck {
+                success,
+                error: error.to_string(),
+                zid: "z5555555".to_string(),
+                ..InitAck::default()
+            })),
+        };
+        Message::Binary(frame.encode_to_vec())
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_the_relay_rejects_the_login() {
+        assert!(matches!(
+            login(vec![
+                ack(false, "invalid zid or token"),
+                close(CloseCode::Policy, "invalid zid or token")
+            ])
+            .await,
+            Login::Rejected { reason } if reason == "invalid zid or token"
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_when_the_connection_is_closed_without_an_ack() {
+        for message in [
+            // the relay timed out waiting for the login, or couldn't read it
+            close(CloseCode::Policy, ""),
+            close(CloseCode::Policy, "invalid zid or token"),
+            close(CloseCode::Away, ""),
+            Message::Close(None),
+        ] {
+            assert!(matches!(login(vec![message]).await, Login::Lost));
+        }
+        assert!(matches!(login(Vec::new()).await, Login::Lost));
+    }
+
+    #[tokio::test]
+    async fn accepts_acknowledged_logins() {
+        let Login::Accepted(ack) = login(vec![Message::Ping(Vec::new()), ack(true, "")]).await
+        else {
+            panic!("the login wasn't accepted");
+        };
+        assert_eq!(ack.zid, "z5555555");
+
+        assert!(matches!(
+            login(vec![Message::Binary(Vec::new())]).await,
+            Login::Unacknowledged(Some(_))
+        ));
+    }
 }
@@ -3,10 +3,18 @@ use log::{error, warn};
 use prost::bytes::Bytes;
 use tokio_tungstenite::tungstenite::Message;
 
-use super::task::handle_task_request;
-use crate::relay::ws_extensions::{socket_frame::Data, SocketFrame};
+use super::task::{handle_task_cancel, handle_task_request};
+use crate::{
+    managers::tasks::{RunningTasks, TaskSettings},
+    relay::ws_extensions::{socket_frame::Data, SocketFrame},
+};
 
-pub(crate) async fn handle_message(msg: Message, tx: UnboundedSender<Message>) {
+pub(crate) async fn handle_message(
+    msg: Message,
+    tx: UnboundedSender<Message>,
+    running: RunningTasks,
+    settings: &TaskSettings,
+) {
     match msg {
         Message::Binary(data) => {
             // attempt to parse the message as a task request
@@ -15,8 +23,12 @@ pub(crate) async fn handle_message(msg: Message, tx: UnboundedSender<Message>) {
                     // if we successfully parsed the message, then we can
                     // process it
                     if let Some(data) = frame.data {
-                        if let Data::TaskRequest(req) = data {
-                            handle_task_request(req, tx).await;
+                        match data {
+                            Data::TaskRequest(req) => {
+                                handle_task_request(req, tx, running, settings).await
+                            },
+                            Data::TaskCancel(cancel) => handle_task_cancel(&cancel, &running),
+                            _ => {},
                         }
                     } else {
                         error!("received invalid message from relay");
@@ -30,6 +42,9 @@ pub(crate) async fn handle_message(msg: Message, tx: UnboundedSender<Message>) {
         Message::Close(_) => {
             warn!("received close message from relay");
         },
+        // pings and pongs only keep the connection alive; tungstenite answers
+        // pings itself
+        Message::Ping(_) | Message::Pong(_) => {},
         _ => {
             // should not be receiving any other message types
             warn!("received unexpected message type");
@@ -1,17 +1,43 @@
 use futures::channel::mpsc::UnboundedSender;
-use log::{error, info};
+use log::{error, info, warn};
+use tokio::sync::oneshot;
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::{
-    managers::tasks::Task,
-    relay::ws_extensions::{socket_frame::Data, SocketFrame, TaskRequest},
+    managers::tasks::{RunningTasks, Task, TaskSettings},
+    relay::ws_extensions::{socket_frame::Data, SocketFrame, TaskCancel, TaskOutput, TaskRequest},
 };
 
-pub(crate) async fn handle_task_request(req: TaskRequest, tx: UnboundedSender<Message>) {
+pub(crate) async fn handle_task_request(
+    req: TaskRequest,
+    tx: UnboundedSender<Message>,
+    running: RunningTasks,
+    settings: &TaskSettings,
+) {
     info!("received task request: {}", req.id);
-    // execute the task
-    let response = Task::from(req).execute().await;
+    // register the task so that it can be cancelled
+    let id = req.id.clone();
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    running.lock().unwrap().insert(id.clone(), cancel_tx);
 
+    // execute the task, relaying any streamed output as it is produced
+    let response = Task::from(req)
+        .execute(
+            settings,
+            |chunk| {
+                let frame = SocketFrame {
+                    data: Some(Data::TaskOutput(TaskOutput {
+                        id:    id.clone(),
+                        chunk: Some(chunk),
+                    })),
+                };
+                send_frame(&tx, &frame, "task output");
+            },
+            cancel_rx,
+        )
+        .await;
+
+    running.lock().unwrap().remove(&id);
     info!("sending task response: {}", response.id);
 
     // create return message
@@ -19,9 +45,24 @@ pub(crate) async fn handle_task_request(req: TaskRequest, tx: UnboundedSender<Me
         data: Some(Data::TaskResponse(response)),
     };
 
-    if let Err(e) = tx.unbounded_send(Message::Binary(prost::Message::encode_to_vec(
-        &return_frame,
-    ))) {
-        error!("failed to send task response: {}", e);
+    send_frame(&tx, &return_frame, "task response");
+}
+
+pub(crate) fn handle_task_cancel(cancel: &TaskCancel, running: &RunningTasks) {
+    info!("received cancellation for task: {}", cancel.id);
+    match running.lock().unwrap().remove(&cancel.id) {
+        Some(chan) => {
+            // the task may have just finished, in which case there is nothing to do
+            let _ = chan.send(());
+        },
+        None => warn!("received cancellation for unknown task: {}", cancel.id),
+    }
+}
+
+/// Encodes and sends a frame to the relay. `description` is used when logging
+/// failures.
+fn send_frame(tx: &UnboundedSender<Message>, frame: &SocketFrame, description: &str) {
+    if let Err(e) = tx.unbounded_send(Message::Binary(prost::Message::encode_to_vec(frame))) {
+        error!("failed to send {}: {}", description, e);
     }
 }
@@ -1,34 +1,104 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
 use clap::Parser;
 use human_panic::setup_panic;
+use log::error;
 
-use crate::managers::ConnectionManager;
+use crate::{
+    config_management::ConfigArgs,
+    managers::{backoff::Backoff, limits::Limits, scratch, tasks::TaskSettings, ConnectionManager},
+    policy::Policy,
+};
 
 /// A VLab relay runner. This app should run on your VLab instance, under your
 /// account name. It is highly recommended that you run this in some sort of
 /// detachable interface, such as zellij or screen.
 #[derive(Parser, Debug)]
 #[clap(name = "vlab relay runner", author, version, about, long_about = None, verbatim_doc_comment)]
-struct Args {}
+struct Args {
+    #[clap(flatten)]
+    config:              ConfigArgs,
+    /// The number of seconds to wait before reconnecting to the relay. The
+    /// wait doubles after each failed attempt in a row.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_delay:     u64,
+    /// The longest number of seconds to wait before reconnecting.
+    #[clap(long, default_value_t = 60)]
+    max_reconnect_delay: u64,
+    /// Stop after this many failed attempts in a row to connect to the relay.
+    /// The runner retries forever if omitted.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts:        Option<u32>,
+    /// The policy file listing the commands the relay may run. Defaults to
+    /// `policy.toml` next to the config file.
+    #[clap(long, env = "RELAY_POLICY")]
+    policy:              Option<PathBuf>,
+    /// The directory that tasks' working directories are created in. Defaults
+    /// to `vlab-relay` in `$XDG_RUNTIME_DIR`, or a directory for your user in
+    /// `/tmp`.
+    #[clap(long, env = "RELAY_WORK_DIR")]
+    work_dir:            Option<PathBuf>,
+    #[clap(flatten)]
+    limits:              Limits,
+}
 
 mod config_management;
 mod handlers;
 mod managers;
+mod policy;
 mod relay;
 mod startup;
 
+// `setup_panic!` expands to the deprecated `PanicInfo` alias
+#[allow(deprecated)]
 #[tokio::main]
 async fn main() {
     setup_panic!();
     simple_logger::init_with_level(log::Level::Info).expect("failed to initialize logger");
-    Args::parse();
+    let args = Args::parse();
 
     // header output
     startup::print_header();
 
     // create config
-    let config = config_management::get_config();
-    let mut conn_manager = ConnectionManager::new(config);
+    let config = match config_management::get_config(args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("failed to configure the runner: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let policy = match Policy::load(args.policy) {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("failed to load the command policy: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let work_dir = match scratch::prepare_root(args.work_dir) {
+        Ok(work_dir) => work_dir,
+        Err(e) => {
+            error!("failed to prepare the working directory: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let backoff = Backoff::new(
+        Duration::from_secs(args.reconnect_delay),
+        Duration::from_secs(args.max_reconnect_delay),
+        args.max_attempts,
+    );
+    let mut conn_manager = ConnectionManager::new(
+        config,
+        backoff,
+        Arc::new(TaskSettings {
+            policy,
+            limits: args.limits,
+            work_dir,
+        }),
+    );
 
-    // connect to relay
-    conn_manager.connect_and_listen().await;
+    // connect to relay, until there's no point trying again
+    let reason = conn_manager.connect_and_listen().await;
+    error!("{}", reason);
+    std::process::exit(1);
 }
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Decides how long to wait between attempts to reconnect to the relay.
+///
+/// The delay doubles after every failed attempt, up to a maximum, and is
+/// randomised so that runners disconnected at the same time don't all retry at
+/// the same time.
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    initial:      Duration,
+    max:          Duration,
+    /// How many failed attempts in a row are allowed before giving up, if
+    /// there is a limit.
+    max_attempts: Option<u32>,
+    /// How many attempts in a row have failed.
+    failures:     u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration, max_attempts: Option<u32>) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            max_attempts,
+            failures: 0,
+        }
+    }
+
+    /// Records a failed attempt, and returns how long to wait before the next
+    /// one, or `None` if there shouldn't be another.
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+        if self.max_attempts.is_some_and(|max| self.failures >= max) {
+            return None;
+        }
+
+        let exponent = (self.failures - 1).min(31);
+        let delay = self.initial.saturating_mul(1 << exponent).min(self.max);
+
+        // wait somewhere between half and all of the delay
+        let half = delay / 2;
+        Some(half + rand::thread_rng().gen_range(Duration::ZERO..=half))
+    }
+
+    /// How many attempts in a row have failed.
+    pub(crate) fn failures(&self) -> u32 { self.failures }
+
+    /// Starts again from the initial delay, e.g. once a connection has proven
+    /// stable.
+    pub(crate) fn reset(&mut self) { self.failures = 0; }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    /// Checks that `delay` is between half and all of `expected`.
+    fn assert_jittered(delay: Option<Duration>, expected: Duration) {
+        let delay = delay.unwrap();
+        assert!(
+            delay >= expected / 2 && delay <= expected,
+            "{delay:?} isn't within {expected:?}"
+        );
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), None);
+
+        for expected in [1, 2, 4, 8, 10, 10] {
+            assert_jittered(backoff.next_delay(), Duration::from_secs(expected));
+        }
+        assert_eq!(backoff.failures(), 6);
+
+        // the delay can't overflow, however many attempts fail
+        for _ in 0..100 {
+            assert_jittered(backoff.next_delay(), Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn jitter_varies_the_delay() {
+        let mut delays = (0..20)
+            .map(|_| {
+                Backoff::new(Duration::from_secs(8), Duration::from_secs(8), None)
+                    .next_delay()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        delays.dedup();
+
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn the_maximum_is_at_least_the_initial_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(1), None);
+
+        assert_jittered(backoff.next_delay(), Duration::from_secs(5));
+        assert_jittered(backoff.next_delay(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn gives_up_after_the_maximum_attempts() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), Some(3));
+
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+        assert!(backoff.next_delay().is_none());
+    }
+
+    #[test]
+    fn reset_starts_from_the_initial_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), Some(3));
+        backoff.next_delay();
+        backoff.next_delay();
+
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+        assert_jittered(backoff.next_delay(), Duration::from_secs(1));
+        assert!(backoff.next_delay().is_some());
+    }
+}
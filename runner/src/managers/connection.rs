@@ -1,42 +1,110 @@
-use std::time::Duration;
+use std::{
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use colored::Colorize;
-use log::error;
+use log::{error, warn};
 use spinners::{Spinner, Spinners};
 
-use crate::{config_management::Configuration, handlers::connection::handle_connection};
+use super::backoff::Backoff;
+use crate::{
+    config_management::Configuration,
+    handlers::connection::{handle_connection, Disconnect},
+    managers::tasks::TaskSettings,
+};
+
+/// How long a connection must last before it's considered stable, and the
+/// delay between reconnection attempts starts again from the beginning.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
 
 /// A manager to manage all network activity with the relay.
 #[derive(Debug, Clone)]
 pub(crate) struct ConnectionManager {
-    config: Configuration,
+    config:   Configuration,
+    backoff:  Backoff,
+    settings: Arc<TaskSettings>,
+}
+
+/// Why the runner stopped trying to connect to the relay.
+#[derive(Debug)]
+pub(crate) enum GaveUp {
+    /// The relay refused the runner's credentials.
+    Rejected { reason: String },
+    /// Too many attempts to connect failed in a row.
+    TooManyAttempts { attempts: u32 },
+}
+
+impl Display for GaveUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GaveUp::Rejected { reason } if reason.is_empty() => {
+                write!(f, "rejected by the relay; check your zID and token")
+            },
+            GaveUp::Rejected { reason } => write!(f, "rejected by the relay: {reason}"),
+            GaveUp::TooManyAttempts { attempts } => write!(
+                f,
+                "giving up after {attempts} failed attempts to connect to the relay"
+            ),
+        }
+    }
 }
 
 impl ConnectionManager {
-    pub(crate) fn new(config: Configuration) -> Self { Self { config } }
+    pub(crate) fn new(
+        config: Configuration,
+        backoff: Backoff,
+        settings: Arc<TaskSettings>,
+    ) -> Self {
+        Self {
+            config,
+            backoff,
+            settings,
+        }
+    }
 
-    pub(crate) async fn connect_and_listen(&mut self) {
+    /// Connects to the relay and handles its requests, reconnecting whenever
+    /// the connection is lost, until the relay rejects the runner or too many
+    /// attempts fail.
+    pub(crate) async fn connect_and_listen(&mut self) -> GaveUp {
         loop {
             // attempt to connect to the relay
             let spinner = Spinner::new(Spinners::Dots, "Connecting to relay".to_string());
             let connection = tokio_tungstenite::connect_async(self.config.get_url().clone()).await;
 
             match connection {
-                // if we failed to connect, wait a bit and try again
-                Err(e) => handle_connection_error(spinner, e).await,
+                Err(e) => {
+                    spinner.stop_with_message("❌ Failed to connect to relay\n".red().to_string());
+                    error!("failed to connect to relay: {}", e);
+                },
                 Ok((stream, ..)) => {
-                    handle_connection(spinner, stream, self.config.token.clone()).await
+                    let connected_at = Instant::now();
+                    match handle_connection(
+                        spinner,
+                        stream,
+                        self.config.token.clone(),
+                        self.settings.clone(),
+                    )
+                    .await
+                    {
+                        Disconnect::Rejected { reason } => return GaveUp::Rejected { reason },
+                        Disconnect::Lost => warn!("disconnected from relay"),
+                    }
+
+                    if connected_at.elapsed() >= STABLE_CONNECTION {
+                        self.backoff.reset();
+                    }
                 },
             }
+
+            let Some(delay) = self.backoff.next_delay() else {
+                return GaveUp::TooManyAttempts {
+                    attempts: self.backoff.failures(),
+                };
+            };
+            warn!("will attempt to reconnect in {:.1?}", delay);
+            tokio::time::sleep(delay).await;
         }
     }
 }
-
-async fn handle_connection_error(spinner: Spinner, e: tokio_tungstenite::tungstenite::Error) {
-    spinner.stop_with_message("❌ Failed to connect to relay\n".red().to_string());
-    error!(
-        "failed to connect to relay; will attempt to reconnect in 5 seconds: {}",
-        e
-    );
-    tokio::time::sleep(Duration::from_secs(5)).await;
-}
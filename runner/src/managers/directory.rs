@@ -0,0 +1,519 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs::{DirBuilder, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use common::symlinks::{contains_target, Tree};
+
+use crate::relay::core::{Directory, File};
+
+/// How deeply directories may be nested in an uploaded directory.
+const MAX_DEPTH: usize = 32;
+
+/// Why an uploaded directory is unsafe to create. `path` is the offending
+/// entry, relative to the task's working directory.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InvalidPath {
+    /// The name is an absolute path.
+    Absolute { path: String },
+    /// The name refers to a parent directory, i.e. contains `..`.
+    Traversal { path: String },
+    /// The name is empty, `.`, or contains a path separator or a NUL byte.
+    InvalidName { path: String },
+    /// Two entries in the same directory have the same name.
+    Duplicate { path: String },
+    /// Directories are nested more than [`MAX_DEPTH`] deep.
+    TooDeep { path: String, max_depth: usize },
+    /// A symlink points outside the directory, or through another symlink.
+    SymlinkEscape { path: String },
+}
+
+impl Display for InvalidPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidPath::Absolute { path } => write!(f, "{path:?} is an absolute path"),
+            InvalidPath::Traversal { path } => {
+                write!(f, "{path:?} refers to a parent directory")
+            },
+            InvalidPath::InvalidName { path } => write!(f, "{path:?} is not a valid name"),
+            InvalidPath::Duplicate { path } => write!(f, "{path:?} appears more than once"),
+            InvalidPath::TooDeep { path, max_depth } => write!(
+                f,
+                "{path:?} is nested more than {max_depth} directories deep"
+            ),
+            InvalidPath::SymlinkEscape { path } => {
+                write!(f, "{path:?} is a symlink that points outside the upload")
+            },
+        }
+    }
+}
+
+/// Why an uploaded directory couldn't be created.
+#[derive(Debug)]
+pub(crate) enum DirectoryError {
+    Invalid(InvalidPath),
+    Io {
+        path:   PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl Display for DirectoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DirectoryError::Invalid(e) => write!(f, "invalid upload: {e}"),
+            DirectoryError::Io { path, source } => {
+                write!(f, "failed to create {}: {}", path.display(), source)
+            },
+        }
+    }
+}
+
+impl Directory {
+    /// Creates the directory inside `parent`, along with all files and
+    /// directories in it.
+    ///
+    /// The whole tree is validated first, so nothing is created if any of it
+    /// could end up outside the directory.
+    pub(crate) fn realise(self, parent: &Path) -> Result<(), DirectoryError> {
+        self.validate().map_err(DirectoryError::Invalid)?;
+
+        let path = parent.join(&self.name);
+        self.create(&path)
+    }
+
+    /// Checks that everything in the directory stays inside it once created.
+    /// The directory's own name is not checked, since it's chosen by the
+    /// runner.
+    fn validate(&self) -> Result<(), InvalidPath> { self.validate_entries(self, &[]) }
+
+    /// Validates the entries of this directory, which is at `parents` inside
+    /// `root`.
+    fn validate_entries(&self, root: &Directory, parents: &[&str]) -> Result<(), InvalidPath> {
+        let path = parents.join("/");
+        if parents.len() > MAX_DEPTH {
+            return Err(InvalidPath::TooDeep {
+                path,
+                max_depth: MAX_DEPTH,
+            });
+        }
+
+        let mut names = HashSet::new();
+        let entries = self
+            .files
+            .iter()
+            .map(|f| &f.file_name)
+            .chain(self.directories.iter().map(|d| &d.name));
+        for name in entries {
+            let entry_path = join(&path, name);
+            check_name(name, &entry_path)?;
+            if !names.insert(name) {
+                return Err(InvalidPath::Duplicate { path: entry_path });
+            }
+        }
+
+        for file in self.files.iter().filter(|f| !f.symlink_target.is_empty()) {
+            if !contains_target(root, parents, &file.symlink_target) {
+                return Err(InvalidPath::SymlinkEscape {
+                    path: join(&path, &file.file_name),
+                });
+            }
+        }
+
+        for dir in &self.directories {
+            let parents = [parents, &[dir.name.as_str()]].concat();
+            dir.validate_entries(root, &parents)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the (validated) directory at `path`. Nothing that already
+    /// exists is written to.
+    fn create(self, path: &Path) -> Result<(), DirectoryError> {
+        let io_error = |source| DirectoryError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        DirBuilder::new().create(path).map_err(io_error)?;
+
+        for file in self.files {
+            let file_path = path.join(&file.file_name);
+            create_file(&file_path, &file).map_err(|source| DirectoryError::Io {
+                path: file_path,
+                source,
+            })?;
+        }
+
+        for dir in self.directories {
+            let dir_path = path.join(&dir.name);
+            dir.create(&dir_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Tree for Directory {
+    fn name(&self) -> &str { &self.name }
+
+    fn directories(&self) -> &[Self] { &self.directories }
+
+    fn symlinks(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.files
+            .iter()
+            .filter(|f| !f.symlink_target.is_empty())
+            .map(|f| (f.file_name.as_str(), f.symlink_target.as_str()))
+    }
+}
+
+/// Creates a file, or a symlink, that doesn't already exist. Only the file's
+/// permission bits are applied from its mode, never setuid, setgid or sticky.
+fn create_file(path: &Path, file: &File) -> Result<(), std::io::Error> {
+    if !file.symlink_target.is_empty() {
+        return std::os::unix::fs::symlink(&file.symlink_target, path);
+    }
+
+    let mut created = OpenOptions::new().write(true).create_new(true).open(path)?;
+    created.write_all(&file.data)?;
+    if file.mode != 0 {
+        created.set_permissions(Permissions::from_mode(file.mode & 0o777))?;
+    }
+
+    Ok(())
+}
+
+/// Checks that `name` names a single entry inside its directory.
+fn check_name(name: &str, path: &str) -> Result<(), InvalidPath> {
+    let as_path = Path::new(name);
+    if as_path.has_root() {
+        return Err(InvalidPath::Absolute {
+            path: path.to_string(),
+        });
+    }
+    if as_path.components().any(|c| c == Component::ParentDir) {
+        return Err(InvalidPath::Traversal {
+            path: path.to_string(),
+        });
+    }
+    if name.is_empty()
+        || name == "."
+        || name.contains(std::path::is_separator)
+        || name.contains('\0')
+    {
+        return Err(InvalidPath::InvalidName {
+            path: path.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Joins a name onto a path relative to the task's working directory.
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{path}/{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use common::symlinks::escaping_links;
+
+    use super::{DirectoryError, InvalidPath, MAX_DEPTH};
+    use crate::relay::core::{Directory, File};
+
+    fn file(name: &str) -> File {
+        File {
+            file_name: name.to_string(),
+            data: name.as_bytes().to_vec(),
+            ..File::default()
+        }
+    }
+
+    fn dir(name: &str, files: Vec<File>, directories: Vec<Directory>) -> Directory {
+        Directory {
+            name: name.to_string(),
+            files,
+            directories,
+        }
+    }
+
+    /// Realises `upload` in a fresh temporary directory, returning the error
+    /// and checking that nothing at all was created.
+    fn rejects(upload: Directory) -> InvalidPath {
+        let parent = tempfile::tempdir().unwrap();
+        let Err(DirectoryError::Invalid(e)) = upload.realise(parent.path()) else {
+            panic!("the upload should have been rejected");
+        };
+
+        assert_eq!(std::fs::read_dir(parent.path()).unwrap().count(), 0);
+        e
+    }
+
+    #[test]
+    fn realises_nested_directories() {
+        let parent = tempfile::tempdir().unwrap();
+        let upload = dir(
+            "task",
+            vec![file("a.c")],
+            vec![dir(
+                "tests",
+                vec![file("b.txt")],
+                vec![dir("empty", vec![], vec![])],
+            )],
+        );
+
+        upload.realise(parent.path()).unwrap();
+
+        let root = parent.path().join("task");
+        assert_eq!(std::fs::read(root.join("a.c")).unwrap(), b"a.c");
+        assert_eq!(std::fs::read(root.join("tests/b.txt")).unwrap(), b"b.txt");
+        assert!(root.join("tests/empty").is_dir());
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert_eq!(
+            rejects(dir("task", vec![file("/etc/passwd")], vec![])),
+            InvalidPath::Absolute {
+                path: "/etc/passwd".to_string(),
+            }
+        );
+        assert_eq!(
+            rejects(dir("task", vec![], vec![dir("/tmp", vec![], vec![])])),
+            InvalidPath::Absolute {
+                path: "/tmp".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        assert_eq!(
+            rejects(dir("task", vec![file("../../.bashrc")], vec![])),
+            InvalidPath::Traversal {
+                path: "../../.bashrc".to_string(),
+            }
+        );
+        assert_eq!(
+            rejects(dir(
+                "task",
+                vec![],
+                vec![dir(
+                    "src",
+                    vec![],
+                    vec![dir("..", vec![file(".bashrc")], vec![])]
+                )]
+            )),
+            InvalidPath::Traversal {
+                path: "src/..".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_separators_in_names() {
+        assert_eq!(
+            rejects(dir("task", vec![file("src/main.c")], vec![])),
+            InvalidPath::InvalidName {
+                path: "src/main.c".to_string(),
+            }
+        );
+        assert_eq!(
+            rejects(dir("task", vec![], vec![dir("a/b", vec![], vec![])])),
+            InvalidPath::InvalidName {
+                path: "a/b".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_special_names() {
+        for name in ["", ".", "nul\0byte"] {
+            assert_eq!(
+                rejects(dir("task", vec![file(name)], vec![])),
+                InvalidPath::InvalidName {
+                    path: name.to_string(),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        assert_eq!(
+            rejects(dir("task", vec![file("a.c"), file("a.c")], vec![])),
+            InvalidPath::Duplicate {
+                path: "a.c".to_string(),
+            }
+        );
+        assert_eq!(
+            rejects(dir(
+                "task",
+                vec![],
+                vec![dir("src", vec![file("x")], vec![dir("x", vec![], vec![])])]
+            )),
+            InvalidPath::Duplicate {
+                path: "src/x".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_overly_deep_trees() {
+        let nest = |levels: usize| {
+            (0..levels).fold(dir("d", vec![file("f")], vec![]), |inner, _| {
+                dir("d", vec![], vec![inner])
+            })
+        };
+
+        let parent = tempfile::tempdir().unwrap();
+        dir("task", vec![], vec![nest(MAX_DEPTH - 1)])
+            .realise(parent.path())
+            .unwrap();
+
+        let InvalidPath::TooDeep { path, max_depth } =
+            rejects(dir("task", vec![], vec![nest(MAX_DEPTH)]))
+        else {
+            panic!("the upload should be too deep");
+        };
+        assert_eq!(max_depth, MAX_DEPTH);
+        assert_eq!(path.split('/').count(), MAX_DEPTH + 1);
+    }
+
+    fn symlink(name: &str, target: &str) -> File {
+        File {
+            file_name: name.to_string(),
+            symlink_target: target.to_string(),
+            ..File::default()
+        }
+    }
+
+    #[test]
+    fn applies_permission_bits() {
+        let parent = tempfile::tempdir().unwrap();
+        let script = File {
+            mode: 0o755,
+            ..file("test.sh")
+        };
+        let setuid = File {
+            mode: 0o4750,
+            ..file("setuid")
+        };
+        dir("task", vec![script, setuid, file("plain")], vec![])
+            .realise(parent.path())
+            .unwrap();
+
+        let mode = |name| {
+            let path = parent.path().join("task").join(name);
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+        };
+        assert_eq!(mode("test.sh"), 0o755);
+        assert_eq!(mode("setuid"), 0o750);
+        // files without a mode are left with the default permissions
+        assert_eq!(mode("plain") & 0o111, 0);
+    }
+
+    #[test]
+    fn creates_symlinks_inside_the_upload() {
+        let parent = tempfile::tempdir().unwrap();
+        dir(
+            "task",
+            vec![
+                file("a.c"),
+                symlink("link.c", "a.c"),
+                symlink("tests", "sub"),
+            ],
+            vec![dir("sub", vec![symlink("up.c", "../a.c")], vec![])],
+        )
+        .realise(parent.path())
+        .unwrap();
+
+        let root = parent.path().join("task");
+        assert_eq!(std::fs::read(root.join("link.c")).unwrap(), b"a.c");
+        assert_eq!(std::fs::read(root.join("sub/up.c")).unwrap(), b"a.c");
+        assert_eq!(std::fs::read(root.join("tests/up.c")).unwrap(), b"a.c");
+    }
+
+    #[test]
+    fn rejects_symlinks_outside_the_upload() {
+        for target in ["/etc/passwd", "..", "../.bashrc", "sub/../../.bashrc"] {
+            assert_eq!(
+                rejects(dir("task", vec![symlink("link", target)], vec![])),
+                InvalidPath::SymlinkEscape {
+                    path: "link".to_string(),
+                }
+            );
+        }
+
+        assert_eq!(
+            rejects(dir(
+                "task",
+                vec![],
+                vec![dir("sub", vec![symlink("link", "../../.bashrc")], vec![])]
+            )),
+            InvalidPath::SymlinkEscape {
+                path: "sub/link".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_symlinks_through_other_symlinks() {
+        // `here/..` looks like it stays inside, but `here` is the root itself,
+        // so it really points at the root's parent
+        assert_eq!(
+            rejects(dir(
+                "task",
+                vec![symlink("here", "."), symlink("link", "here/..")],
+                vec![]
+            )),
+            InvalidPath::SymlinkEscape {
+                path: "link".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn agrees_with_the_client_on_which_symlinks_escape() {
+        let trees = [
+            ("link", "a.c"),
+            ("link", "./sub/../a.c"),
+            ("link", "."),
+            ("link", "/etc/passwd"),
+            ("link", ".."),
+            ("link", "sub/../../a.c"),
+            ("link", "here/.."),
+            ("link", "here/a.c"),
+        ]
+        .map(|(name, target)| {
+            dir(
+                "task",
+                vec![file("a.c"), symlink("here", "."), symlink(name, target)],
+                vec![dir("sub", vec![symlink("up", "../a.c")], vec![])],
+            )
+        });
+
+        for tree in trees {
+            // the client skips these links, rather than uploading them
+            let skipped = escaping_links(&tree);
+            match tree.validate() {
+                Ok(()) => assert!(skipped.is_empty(), "{skipped:?}"),
+                Err(InvalidPath::SymlinkEscape { path }) => {
+                    assert_eq!(skipped, [PathBuf::from(path)]);
+                },
+                Err(e) => panic!("unexpected rejection: {e}"),
+            }
+        }
+    }
+}
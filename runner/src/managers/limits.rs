@@ -0,0 +1,115 @@
+use std::io;
+
+/// Limits on the resources each command may use. Commands that exceed a limit
+/// are killed, or have the offending system call fail.
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct Limits {
+    /// The number of seconds of CPU time each command may use.
+    #[clap(long, value_name = "SECONDS")]
+    cpu_limit:       Option<u64>,
+    /// The size of each command's address space, in MiB. Programs built with
+    /// sanitizers (e.g. `dcc`) reserve far more than they use, and may not
+    /// start with a limit.
+    #[clap(long, value_name = "MIB")]
+    memory_limit:    Option<u64>,
+    /// The number of processes your user may have while a command runs. This
+    /// counts all of your processes, not just the command's.
+    #[clap(long, value_name = "COUNT")]
+    process_limit:   Option<u64>,
+    /// The largest file each command may write, in MiB.
+    #[clap(long, value_name = "MIB")]
+    file_size_limit: Option<u64>,
+    /// The most output kept from each command, in KiB. Anything after that is
+    /// discarded, and replaced with a note saying so.
+    #[clap(long, value_name = "KIB", default_value_t = 4096, value_parser = clap::value_parser!(u64).range(1..))]
+    output_limit:    u64,
+}
+
+const MIB: u64 = 1024 * 1024;
+
+impl Limits {
+    /// The most bytes of output kept from each command.
+    pub(crate) fn max_output(&self) -> usize {
+        usize::try_from(self.output_limit.saturating_mul(1024)).unwrap_or(usize::MAX)
+    }
+
+    /// Makes `command` apply the limits to the process it spawns.
+    pub(crate) fn apply(&self, command: &mut tokio::process::Command) {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_limit),
+            (
+                libc::RLIMIT_AS,
+                self.memory_limit.map(|m| m.saturating_mul(MIB)),
+            ),
+            (libc::RLIMIT_NPROC, self.process_limit),
+            (
+                libc::RLIMIT_FSIZE,
+                self.file_size_limit.map(|m| m.saturating_mul(MIB)),
+            ),
+        ];
+        if limits.iter().all(|(_, limit)| limit.is_none()) {
+            return;
+        }
+
+        // SAFETY: the closure runs in the child between `fork` and `exec`, and
+        // only calls `setrlimit`, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in limits {
+                    let Some(limit) = limit else { continue };
+                    let limit = libc::rlim_t::try_from(limit).unwrap_or(libc::RLIM_INFINITY);
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: limit,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::Limits;
+
+    #[derive(Parser)]
+    struct Args {
+        #[clap(flatten)]
+        limits: Limits,
+    }
+
+    async fn ulimits(args: &[&str]) -> String {
+        let limits = Args::parse_from([&["runner"], args].concat()).limits;
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "ulimit -t; ulimit -v; ulimit -f"]);
+        limits.apply(&mut command);
+
+        let output = command.output().await.unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[tokio::test]
+    async fn applies_limits_to_children() {
+        // `ulimit -v` is in KiB, and `ulimit -f` in 512 byte blocks
+        assert_eq!(
+            ulimits(&[
+                "--cpu-limit",
+                "30",
+                "--memory-limit",
+                "512",
+                "--file-size-limit",
+                "2"
+            ])
+            .await,
+            "30\n524288\n4096\n"
+        );
+    }
+}
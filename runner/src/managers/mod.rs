@@ -1,4 +1,8 @@
+pub(crate) mod backoff;
 mod connection;
+mod directory;
+pub(crate) mod limits;
+pub(crate) mod scratch;
 pub(crate) mod tasks;
 
 pub(crate) use connection::ConnectionManager;
@@ -0,0 +1,177 @@
+use std::{
+    fs::DirBuilder,
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info};
+
+type Error = Box<dyn std::error::Error>;
+
+/// The prefix of every task's working directory.
+const PREFIX: &str = "runner-tmp-";
+
+/// Returns the default directory that tasks' working directories are created
+/// in: `vlab-relay` in `$XDG_RUNTIME_DIR`, or a directory for the current user
+/// in `/tmp`.
+fn default_root() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("vlab-relay"),
+        _ => std::env::temp_dir().join(format!("vlab-relay-{}", whoami::username())),
+    }
+}
+
+/// Creates the directory that tasks' working directories are created in,
+/// defaulting to [`default_root`], and makes sure only the current user can
+/// use it.
+///
+/// Working directories left behind by a previous run are deleted, since no
+/// task can be using them any more.
+pub(crate) fn prepare_root(root: Option<PathBuf>) -> Result<PathBuf, Error> {
+    let root = root.unwrap_or_else(default_root);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&root)
+        .map_err(|e| format!("failed to create {}: {}", root.display(), e))?;
+
+    // the directory may have existed already, e.g. if someone else created it in
+    // /tmp first
+    let metadata = std::fs::symlink_metadata(&root)?;
+    if !metadata.is_dir() {
+        return Err(format!("{} is not a directory", root.display()).into());
+    }
+    // SAFETY: `getuid` is always successful, and has no memory safety
+    // requirements
+    if metadata.uid() != unsafe { libc::getuid() } {
+        return Err(format!("{} belongs to another user", root.display()).into());
+    }
+    if metadata.mode() & 0o077 != 0 {
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    let mut removed = 0;
+    for entry in std::fs::read_dir(&root)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(PREFIX) {
+            continue;
+        }
+
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(()) => removed += 1,
+            Err(e) => error!(
+                "failed to delete stale directory {}: {}",
+                entry.path().display(),
+                e
+            ),
+        }
+    }
+    if removed > 0 {
+        info!(
+            "deleted {} stale working directories from {}",
+            removed,
+            root.display()
+        );
+    }
+
+    Ok(root)
+}
+
+/// A task's working directory, which is deleted when this is dropped, however
+/// the task ends.
+#[derive(Debug)]
+pub(crate) struct ScratchDir {
+    name: String,
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Reserves the working directory for the task `id` in `root`. The
+    /// directory itself isn't created.
+    pub(crate) fn new(root: &Path, id: &str) -> Result<Self, String> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("invalid task id {id:?}"));
+        }
+
+        let name = format!("{PREFIX}{id}");
+        Ok(Self {
+            path: root.join(&name),
+            name,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str { &self.name }
+
+    pub(crate) fn path(&self) -> &Path { &self.path }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.path) {
+            Ok(()) => {},
+            // the directory may never have been created
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => error!(
+                "failed to delete temporary directory {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{prepare_root, ScratchDir};
+
+    #[test]
+    fn prepares_a_private_root_without_stale_directories() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("scratch");
+        std::fs::create_dir_all(root.join("runner-tmp-old/nested")).unwrap();
+        std::fs::create_dir(root.join("keep")).unwrap();
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(prepare_root(Some(root.clone())).unwrap(), root);
+
+        let mode = std::fs::metadata(&root).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert!(!root.join("runner-tmp-old").exists());
+        assert!(root.join("keep").exists());
+    }
+
+    #[test]
+    fn rejects_roots_that_are_not_directories() {
+        let parent = tempfile::tempdir().unwrap();
+        let file = parent.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        assert!(prepare_root(Some(file)).is_err());
+    }
+
+    #[test]
+    fn deletes_the_directory_when_dropped() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = ScratchDir::new(root.path(), "3f2b-77").unwrap();
+        let path = dir.path().to_owned();
+        std::fs::create_dir_all(path.join("nested")).unwrap();
+        std::fs::write(path.join("nested/file"), "").unwrap();
+
+        drop(dir);
+        assert!(!path.exists());
+
+        // dropping a directory that was never created is fine
+        drop(ScratchDir::new(root.path(), "never-created").unwrap());
+    }
+
+    #[test]
+    fn rejects_task_ids_that_are_not_plain_names() {
+        let root = tempfile::tempdir().unwrap();
+        for id in ["", "..", "a/../../b", "/etc"] {
+            assert!(ScratchDir::new(root.path(), id).is_err(), "{id}");
+        }
+    }
+}
@@ -1,107 +1,237 @@
 use std::{
-    fs::DirBuilder,
-    path::{Path, PathBuf},
+    collections::HashMap,
+    path::PathBuf,
     process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use colored::Colorize;
-use log::{error, info};
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::oneshot,
+};
 
-use crate::relay::{
-    core::{CommandRequest, CommandResponse, Directory},
-    ws_extensions::{TaskRequest, TaskResponse},
+use super::{limits::Limits, scratch::ScratchDir};
+use crate::{
+    policy::Policy,
+    relay::{
+        core::{CommandRequest, CommandResponse, OutputChunk, OutputStream, TaskStatus},
+        ws_extensions::{TaskRequest, TaskResponse},
+    },
 };
 
+/// The tasks currently executing, along with the channels used to cancel
+/// them.
+pub(crate) type RunningTasks = Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>;
+
+/// How every task is run.
+#[derive(Debug)]
+pub(crate) struct TaskSettings {
+    /// Which commands may run.
+    pub(crate) policy:   Policy,
+    /// The resources each command may use.
+    pub(crate) limits:   Limits,
+    /// Where each task's working directory is created.
+    pub(crate) work_dir: PathBuf,
+}
+
 #[derive(Debug)]
 pub(crate) struct Task {
-    pub(crate) id:      String,
-    pub(crate) request: CommandRequest,
+    pub(crate) id:            String,
+    pub(crate) request:       CommandRequest,
+    /// Whether output is reported as it is produced, rather than in the final
+    /// response.
+    pub(crate) stream_output: bool,
 }
 
 macro_rules! return_error_response {
     ($id:expr, $msg:expr) => {{
         error!($msg);
+        let message = format!("operation failed: {}", $msg.red().to_string());
         return TaskResponse {
             id:       $id,
             response: Some(CommandResponse {
-                output:    format!("operation failed: {}", $msg.red().to_string()),
+                output:    message.clone(),
+                stderr:    message,
                 exit_code: -1,
+                status:    TaskStatus::Failed as i32,
+                ..Default::default()
             }),
         }}
     };
     ($id:expr, $msg:expr, $($arg:expr),*) => {{
         error!($msg, $($arg),*);
+        let message = format!("operation failed: {}", format!($msg, $($arg),*)).red().to_string();
         return TaskResponse {
             id:       $id,
             response: Some(CommandResponse {
-                output:    format!("operation failed: {}", format!($msg, $($arg),*)).red().to_string(),
+                output:    message.clone(),
+                stderr:    message,
                 exit_code: -1,
+                status:    TaskStatus::Failed as i32,
+                ..Default::default()
             }),
         }}
     };
 }
 
 impl Task {
-    pub(crate) async fn execute(self) -> TaskResponse {
+    /// Executes the task, unless the policy in `settings` doesn't allow its
+    /// command. If the task's output is streamed, each chunk of output is
+    /// passed to `on_output` as it is produced. The task is killed if
+    /// `cancelled` receives a value.
+    pub(crate) async fn execute(
+        self,
+        settings: &TaskSettings,
+        on_output: impl Fn(OutputChunk),
+        cancelled: oneshot::Receiver<()>,
+    ) -> TaskResponse {
+        if let Err(violation) = settings.policy.check(&self.request) {
+            warn!("rejected task {}: {}", self.id, violation);
+            let message = format!("rejected: {violation}\n").red().to_string();
+            return TaskResponse {
+                id:       self.id,
+                response: Some(CommandResponse {
+                    output: message.clone(),
+                    exit_code: -1,
+                    stderr: message,
+                    status: TaskStatus::Rejected as i32,
+                    ..Default::default()
+                }),
+            };
+        }
+
         info!("executing task: {}", self.id);
-        // create a new temporary folder and cd into it
-        let folder_name = format!("runner-tmp-{}", self.id);
+        // the task's working directory is deleted when this is dropped, however the
+        // task ends
+        let scratch = match ScratchDir::new(&settings.work_dir, &self.id) {
+            Ok(scratch) => scratch,
+            Err(e) => return_error_response!(self.id, "failed to create working directory: {}", e),
+        };
 
         // create all of the relevant files in this directory
         let root_dir = match self.request.directory {
             Some(mut d) => {
                 // the root directory will contain everything else, so we will name it the temp
                 // folder
-                d.name = folder_name.clone();
+                d.name = scratch.name().to_string();
                 d
             },
             None => return_error_response!(self.id, "no directory specified"),
         };
 
         // realise the directory
-        match root_dir.realise(Path::new(".")) {
-            Ok(_) => {},
-            Err(e) => return_error_response!(self.id, "failed to create working directory: {}", e),
+        if let Err(e) = root_dir.realise(&settings.work_dir) {
+            return_error_response!(self.id, "failed to create working directory: {}", e)
         }
 
         // all files have been created; now we can execute the command
-        let child = match tokio::process::Command::new(self.request.command)
+        let mut command = tokio::process::Command::new(self.request.command);
+        command
             .args(self.request.arguments)
-            .current_dir(Path::new(&folder_name))
+            .current_dir(scratch.path())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()
-        {
+            // run the command in its own process group, so that the whole group can be
+            // killed if it times out
+            .process_group(0)
+            // don't leave the command running if the task is abandoned
+            .kill_on_drop(true);
+        settings.limits.apply(&mut command);
+
+        let mut child = match command.spawn() {
             Ok(r) => r,
             Err(e) => return_error_response!(self.id, "failed to execute command: {}", e),
         };
 
-        // wait for the child to finish
-        match child.wait_with_output().await {
-            Ok(r) => {
+        // read both outputs until the child closes them, then wait for it to exit.
+        // output that isn't streamed is logged in the order it was produced, and
+        // output past the limit is dropped
+        let log = Mutex::new(Vec::new());
+        let cap = Mutex::new(OutputCap::new(settings.limits.max_output()));
+        let record = |mut chunk: OutputChunk| {
+            if !cap.lock().unwrap().take(&mut chunk) {
+                return;
+            }
+
+            if self.stream_output {
+                on_output(chunk);
+            } else {
+                log.lock().unwrap().push(chunk);
+            }
+        };
+
+        let stdout = child.stdout.take().expect("stdout should be piped");
+        let stderr = child.stderr.take().expect("stderr should be piped");
+        let run = async {
+            let output = tokio::join!(
+                read_output(stdout, OutputStream::Stdout, &record),
+                read_output(stderr, OutputStream::Stderr, &record),
+            );
+            (output, child.wait().await)
+        };
+
+        let timeout = match self.request.timeout_seconds {
+            0 => None,
+            t => Some(Duration::from_secs(u64::from(t))),
+        };
+        let run = async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+                None => Some(run.await),
+            }
+        };
+
+        // run until the command finishes, times out, or the task is cancelled
+        let stopped = tokio::select! {
+            completed = run => match completed {
+                Some(completed) => Ok(completed),
+                None => Err(TaskStatus::TimedOut),
+            },
+            Ok(()) = cancelled => Err(TaskStatus::Cancelled),
+        };
+
+        let (output, status) = match stopped {
+            Ok(completed) => completed,
+            Err(reason) => {
+                warn!("task {} stopped ({:?}); killing it", self.id, reason);
+                if let Some(pid) = child.id() {
+                    kill_process_group(pid);
+                }
+                if let Err(e) = child.wait().await {
+                    error!("failed to wait for stopped task {}: {}", self.id, e);
+                }
+
+                return TaskResponse {
+                    id:       self.id,
+                    response: Some(collect_response(log.into_inner().unwrap(), 0, reason)),
+                };
+            },
+        };
+
+        if let (Err(e), _) | (_, Err(e)) = output {
+            return_error_response!(self.id, "failed to read command output: {}", e)
+        }
+
+        match status {
+            Ok(status) => {
                 info!(
                     "task {} finished with exit code {}",
                     self.id,
-                    r.status.code().unwrap_or(2)
+                    status.code().unwrap_or(2)
                 );
-                // delete the temp folder
-                std::fs::remove_dir_all(folder_name.clone()).unwrap_or_else(|e| {
-                    error!(
-                        "failed to delete temporary directory {}/: {}",
-                        folder_name, e
-                    );
-                });
 
-                let status = r.status.code().unwrap_or(1);
+                let status = status.code().unwrap_or(1);
 
                 TaskResponse {
                     id:       self.id,
-                    response: Some(CommandResponse {
-                        // TODO: decide how to handle stdout/stderr together
-                        output:    String::from_utf8_lossy(&r.stdout).to_string()
-                            + &String::from_utf8_lossy(&r.stderr).to_string(),
-                        exit_code: status as i64,
-                    }),
+                    response: Some(collect_response(
+                        log.into_inner().unwrap(),
+                        i64::from(status),
+                        TaskStatus::Completed,
+                    )),
                 }
             },
             Err(e) => {
@@ -111,36 +241,163 @@ impl Task {
     }
 }
 
-impl Directory {
-    /// Creates the directory and all files and directories in it.
-    pub(crate) fn realise(self, root: impl Into<PathBuf>) -> Result<(), std::io::Error> {
-        // create each file in this directory
-        // create this intial directory
-        let path = Path::join(&root.into(), self.name);
-        DirBuilder::new().recursive(true).create(&path)?;
-
-        // create each file in this directory
-        for file in self.files.into_iter() {
-            std::fs::write(Path::join(&path, file.file_name), file.data)?;
+/// Keeps track of how much of a command's output may still be kept.
+struct OutputCap {
+    max:       usize,
+    remaining: usize,
+    truncated: bool,
+}
+
+impl OutputCap {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            remaining: max,
+            truncated: false,
         }
+    }
 
-        // create each directory in this directory and realise it
-        for dir in self.directories.into_iter() {
-            dir.realise(&path)?;
+    /// Counts `chunk` against the cap, returning whether any of it should be
+    /// kept. The chunk that reaches the cap is cut short, and ends with a note
+    /// saying that the rest of the output was discarded.
+    fn take(&mut self, chunk: &mut OutputChunk) -> bool {
+        if self.truncated {
+            return false;
         }
 
-        Ok(())
+        if chunk.data.len() <= self.remaining {
+            self.remaining -= chunk.data.len();
+            return true;
+        }
+
+        chunk.data.truncate(self.remaining);
+        chunk.data.extend_from_slice(
+            format!("\n[output truncated after {} bytes]\n", self.max).as_bytes(),
+        );
+        self.remaining = 0;
+        self.truncated = true;
+        true
+    }
+}
+
+/// Sends `SIGKILL` to every process in the process group led by `pid`.
+fn kill_process_group(pid: u32) {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return;
+    };
+
+    // SAFETY: `kill` has no memory safety requirements; a negative pid targets
+    // the process group
+    if unsafe { libc::kill(-pid, libc::SIGKILL) } != 0 {
+        error!(
+            "failed to kill process group {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Reads `reader` until it is closed, passing each chunk of output to
+/// `on_output` as it is read.
+async fn read_output(
+    mut reader: impl AsyncRead + Unpin,
+    stream: OutputStream,
+    on_output: &impl Fn(OutputChunk),
+) -> Result<(), std::io::Error> {
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        on_output(OutputChunk {
+            stream:    stream as i32,
+            data:      buffer[..read].to_vec(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX)),
+        });
+    }
+}
+
+/// Builds a response from a command's output log.
+fn collect_response(log: Vec<OutputChunk>, exit_code: i64, status: TaskStatus) -> CommandResponse {
+    let mut output = Vec::new();
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+
+    for chunk in &log {
+        output.extend_from_slice(&chunk.data);
+        if chunk.stream == OutputStream::Stderr as i32 {
+            stderr.extend_from_slice(&chunk.data);
+        } else {
+            stdout.extend_from_slice(&chunk.data);
+        }
+    }
+
+    CommandResponse {
+        output: String::from_utf8_lossy(&output).to_string(),
+        exit_code,
+        stdout: String::from_utf8_lossy(&stdout).to_string(),
+        stderr: String::from_utf8_lossy(&stderr).to_string(),
+        log,
+        status: status as i32,
     }
 }
 
 impl From<TaskRequest> for Task {
     fn from(cr: TaskRequest) -> Self {
         Self {
-            id:      cr.id,
-            request: cr.command.unwrap_or_else(|| {
+            id:            cr.id,
+            request:       cr.command.unwrap_or_else(|| {
                 error!("received a task request without a command");
                 panic!()
             }),
+            stream_output: cr.stream_output,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::OutputCap;
+    use crate::relay::core::OutputChunk;
+
+    fn chunk(data: &str) -> OutputChunk {
+        OutputChunk {
+            data: data.as_bytes().to_vec(),
+            ..OutputChunk::default()
+        }
+    }
+
+    #[test]
+    fn truncates_output_past_the_cap() {
+        let mut cap = OutputCap::new(8);
+
+        let mut first = chunk("hello");
+        assert!(cap.take(&mut first));
+        assert_eq!(first.data, b"hello");
+
+        let mut second = chunk("world");
+        assert!(cap.take(&mut second));
+        assert_eq!(second.data, b"wor\n[output truncated after 8 bytes]\n");
+
+        assert!(!cap.take(&mut chunk("!")));
+    }
+
+    #[test]
+    fn keeps_output_that_fits_exactly() {
+        let mut cap = OutputCap::new(5);
+
+        let mut output = chunk("hello");
+        assert!(cap.take(&mut output));
+        assert_eq!(output.data, b"hello");
+
+        // the marker is only added once there is output to drop
+        let mut more = chunk("!");
+        assert!(cap.take(&mut more));
+        assert_eq!(more.data, b"\n[output truncated after 5 bytes]\n");
+    }
+}
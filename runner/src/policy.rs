@@ -0,0 +1,262 @@
+use std::{fmt::Display, path::PathBuf};
+
+use common::config::config_dir;
+use log::{info, warn};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::relay::core::CommandRequest;
+
+type Error = Box<dyn std::error::Error>;
+
+/// The commands allowed when there is no policy file.
+const DEFAULT_COMMANDS: [&str; 2] = ["autotest", "give"];
+
+/// Decides which commands the relay is allowed to run on this machine.
+///
+/// Without a policy, anyone holding the runner's token could run anything as
+/// the student, so only listed commands are run unless the policy says
+/// otherwise.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Policy {
+    /// What happens to commands that aren't listed.
+    #[serde(default)]
+    default:  Unlisted,
+    #[serde(default, rename = "command")]
+    commands: Vec<Rule>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Unlisted {
+    /// Unlisted commands are rejected.
+    #[default]
+    Deny,
+    /// Unlisted commands are run with any arguments.
+    Allow,
+}
+
+/// A command the policy allows.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// The command exactly as it is requested, e.g. `autotest`.
+    name: String,
+    /// If set, every argument must match one of these patterns in full.
+    args: Option<Vec<Pattern>>,
+}
+
+/// A regular expression that must match the whole of an argument.
+#[derive(Debug)]
+struct Pattern(Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&format!("^(?:{pattern})$"))
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Why the policy rejected a command.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Violation {
+    Command { command: String },
+    Argument { command: String, argument: String },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Command { command } => {
+                write!(f, "the runner's policy doesn't allow running `{command}`")
+            },
+            Violation::Argument { command, argument } => write!(
+                f,
+                "the runner's policy doesn't allow passing `{argument}` to `{command}`"
+            ),
+        }
+    }
+}
+
+impl Policy {
+    /// Loads the policy from `path`, or from `policy.toml` next to the config
+    /// file if no path is given. If that file doesn't exist either, only
+    /// [`DEFAULT_COMMANDS`] are allowed.
+    pub(crate) fn load(path: Option<PathBuf>) -> Result<Self, Error> {
+        let (path, explicit) = match path {
+            Some(path) => (path, true),
+            None => (config_dir().join("policy.toml"), false),
+        };
+
+        if !explicit && !path.exists() {
+            warn!(
+                "no policy file at {}; only allowing {}",
+                path.display(),
+                DEFAULT_COMMANDS.join(", ")
+            );
+            return Ok(Self::default());
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let policy: Self =
+            toml::from_str(&contents).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+
+        if policy.default == Unlisted::Allow {
+            warn!(
+                "the policy in {} allows commands that aren't listed",
+                path.display()
+            );
+        }
+        info!("loaded the command policy from {}", path.display());
+
+        Ok(policy)
+    }
+
+    /// Checks whether the policy allows `request` to run.
+    pub(crate) fn check(&self, request: &CommandRequest) -> Result<(), Violation> {
+        let Some(rule) = self.commands.iter().find(|r| r.name == request.command) else {
+            return match self.default {
+                Unlisted::Allow => Ok(()),
+                Unlisted::Deny => Err(Violation::Command {
+                    command: request.command.clone(),
+                }),
+            };
+        };
+
+        let Some(patterns) = &rule.args else {
+            return Ok(());
+        };
+        match request
+            .arguments
+            .iter()
+            .find(|arg| !patterns.iter().any(|Pattern(p)| p.is_match(arg)))
+        {
+            Some(argument) => Err(Violation::Argument {
+                command:  request.command.clone(),
+                argument: argument.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            default:  Unlisted::Deny,
+            commands: DEFAULT_COMMANDS
+                .iter()
+                .map(|name| Rule {
+                    name: (*name).to_string(),
+                    args: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, Violation};
+    use crate::relay::core::CommandRequest;
+
+    fn request(command: &str, arguments: &[&str]) -> CommandRequest {
+        CommandRequest {
+            command: command.to_string(),
+            arguments: arguments.iter().map(ToString::to_string).collect(),
+            ..CommandRequest::default()
+        }
+    }
+
+    fn policy(toml: &str) -> Policy { toml::from_str(toml).unwrap() }
+
+    #[test]
+    fn denies_unlisted_commands_by_default() {
+        let policy = policy(
+            r#"
+            [[command]]
+            name = "autotest"
+            "#,
+        );
+
+        assert_eq!(policy.check(&request("autotest", &["lab01", "-a"])), Ok(()));
+        for command in ["sh", "./autotest", "/usr/bin/autotest"] {
+            assert_eq!(
+                policy.check(&request(command, &[])),
+                Err(Violation::Command {
+                    command: command.to_string(),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn allows_unlisted_commands_in_allow_mode() {
+        let policy = policy(
+            r#"
+            default = "allow"
+
+            [[command]]
+            name = "give"
+            args = ['cs\d{4}']
+            "#,
+        );
+
+        assert_eq!(policy.check(&request("sh", &["-c", "ls"])), Ok(()));
+        assert!(policy.check(&request("give", &["-c"])).is_err());
+    }
+
+    #[test]
+    fn arguments_must_match_a_pattern_in_full() {
+        let policy = policy(
+            r#"
+            [[command]]
+            name = "give"
+            args = ['cs\d{4}', 'lab\d{2}', '[\w-]+\.c']
+
+            [[command]]
+            name = "1511"
+            args = []
+            "#,
+        );
+
+        assert_eq!(
+            policy.check(&request("give", &["cs1511", "lab01", "hello.c"])),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(&request("give", &["cs1511", "lab01; rm -rf ~"])),
+            Err(Violation::Argument {
+                command:  "give".to_string(),
+                argument: "lab01; rm -rf ~".to_string(),
+            })
+        );
+        assert_eq!(policy.check(&request("1511", &[])), Ok(()));
+        assert!(policy.check(&request("1511", &["autotest"])).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        for invalid in [
+            r#"default = "maybe""#,
+            "[[command]]\nargs = []",
+            "[[command]]\nname = \"give\"\nargs = ['(']",
+            "[[command]]\nname = \"give\"\nflags = []",
+        ] {
+            assert!(toml::from_str::<Policy>(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn only_allows_default_commands_without_a_policy_file() {
+        let policy = Policy::default();
+
+        assert_eq!(policy.check(&request("autotest", &["lab01"])), Ok(()));
+        assert_eq!(policy.check(&request("give", &["cs1511"])), Ok(()));
+        assert!(policy.check(&request("bash", &[])).is_err());
+    }
+}
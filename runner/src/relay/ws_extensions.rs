@@ -1,2 +1,2 @@
-#![allow(clippy::pedantic)]
+#![allow(clippy::pedantic, dead_code)]
 include!(concat!(env!("OUT_DIR"), "/websocket.rs"));
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use snafu::{whatever, ResultExt, Whatever};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use tracing::{error, instrument};
+
+use super::{memory::MemoryUserStore, User, UserStore};
+
+/// A [`UserStore`] that keeps every user in a JSON file.
+///
+/// The file is read once when the store is opened, and rewritten in full after
+/// every change. It is only readable by the user running the server.
+#[derive(Debug)]
+pub(crate) struct FileUserStore {
+    path:   PathBuf,
+    /// Every user in the file, which reads are answered from.
+    memory: MemoryUserStore,
+}
+
+impl FileUserStore {
+    /// Opens the store at `path`, creating it if it doesn't exist.
+    pub(crate) async fn open(path: PathBuf) -> Result<Self, Whatever> {
+        let users: Vec<User> = match tokio::fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_whatever_context(|e| format!("failed to parse {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => whatever!("failed to read {}: {}", path.display(), e),
+        };
+
+        Ok(Self {
+            path,
+            memory: MemoryUserStore::new(users),
+        })
+    }
+
+    /// Writes `users` to the store's file, replacing its contents.
+    #[instrument(skip(users))]
+    async fn persist(&self, users: &HashMap<String, User>) -> Result<(), Whatever> {
+        let mut users = users.values().collect::<Vec<_>>();
+        users.sort_by(|a, b| a.zid.cmp(&b.zid));
+
+        let contents = match serde_json::to_vec_pretty(&users) {
+            Ok(c) => c,
+            Err(e) => whatever!("failed to serialise users: {}", e),
+        };
+
+        // write to a temporary file first, so the store is never left half-written.
+        // the file holds token hashes, so it's created readable only by its owner
+        let tmp_path = self.path.with_extension("tmp");
+        let write = async {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&tmp_path)
+                .await?;
+            file.write_all(&contents).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, &self.path).await
+        };
+
+        match write.await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("[persist] error: {}", e);
+                whatever!("failed to write {}: {}", self.path.display(), e)
+            },
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl UserStore for FileUserStore {
+    async fn get_by_zid(&self, zid: &str) -> Option<User> { self.memory.get_by_zid(zid).await }
+
+    async fn get_by_token_key(&self, key: &str) -> Vec<User> {
+        self.memory.get_by_token_key(key).await
+    }
+
+    async fn get_by_plaintext_token(&self, token: &str) -> Option<User> {
+        self.memory.get_by_plaintext_token(token).await
+    }
+
+    // changes hold the lock until the file is written, so that the file is
+    // written in the same order as the changes are made
+    async fn upsert_user(&self, user: User) -> Result<(), Whatever> {
+        let mut users = self.memory.users.write().await;
+        users.insert(user.zid.clone(), user);
+        self.persist(&users).await
+    }
+
+    async fn list_users(&self, after: Option<&str>, limit: usize) -> Result<Vec<User>, Whatever> {
+        self.memory.list_users(after, limit).await
+    }
+
+    async fn delete_by_zid(&self, zid: &str) -> Result<(), Whatever> {
+        let mut users = self.memory.users.write().await;
+        if users.remove(zid).is_some() {
+            self.persist(&users).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::FileUserStore;
+    use crate::auth::{
+        tests::{check_round_trip, user},
+        tokens::TokenScope,
+        UserStore,
+    };
+
+    #[tokio::test]
+    async fn round_trips_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileUserStore::open(dir.path().join("users.json"))
+            .await
+            .unwrap();
+
+        check_round_trip(&store).await;
+    }
+
+    #[tokio::test]
+    async fn reloads_users_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+
+        let store = FileUserStore::open(path.clone()).await.unwrap();
+        store
+            .upsert_user(user("z1111111", "alice-token"))
+            .await
+            .unwrap();
+        store
+            .upsert_user(user("z2222222", "bob-token"))
+            .await
+            .unwrap();
+        store.delete_by_zid("z2222222").await.unwrap();
+        let token_id = store.get_by_zid("z1111111").await.unwrap().tokens[0]
+            .id
+            .clone();
+        drop(store);
+
+        let store = FileUserStore::open(path.clone()).await.unwrap();
+        let alice = store.get_by_zid("z1111111").await.unwrap();
+        assert_eq!(alice.tokens[0].id, token_id);
+        assert!(alice.tokens[0].accepts("alice-token", TokenScope::Client));
+        assert!(store.get_by_zid("z2222222").await.is_none());
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn rejects_corrupt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        std::fs::write(&path, "not json").unwrap();
+
+        assert!(FileUserStore::open(path).await.is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use snafu::Whatever;
+use tokio::sync::RwLock;
+
+use super::{User, UserStore};
+
+/// A [`UserStore`] that only keeps users in memory, for development and
+/// testing. Every user is lost when the server stops.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryUserStore {
+    /// Users, keyed by zid.
+    pub(super) users: RwLock<HashMap<String, User>>,
+}
+
+impl MemoryUserStore {
+    /// Creates a store holding `users`.
+    pub(super) fn new(users: impl IntoIterator<Item = User>) -> Self {
+        Self {
+            users: RwLock::new(users.into_iter().map(|u| (u.zid.clone(), u)).collect()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl UserStore for MemoryUserStore {
+    async fn get_by_zid(&self, zid: &str) -> Option<User> {
+        self.users.read().await.get(zid).cloned()
+    }
+
+    async fn get_by_token_key(&self, key: &str) -> Vec<User> {
+        self.users
+            .read()
+            .await
+            .values()
+            .filter(|u| u.tokens.iter().any(|t| t.hash.lookup == key))
+            .cloned()
+            .collect()
+    }
+
+    async fn get_by_plaintext_token(&self, token: &str) -> Option<User> {
+        self.users
+            .read()
+            .await
+            .values()
+            .find(|u| u.token.as_deref() == Some(token))
+            .cloned()
+    }
+
+    async fn upsert_user(&self, user: User) -> Result<(), Whatever> {
+        self.users.write().await.insert(user.zid.clone(), user);
+        Ok(())
+    }
+
+    async fn list_users(&self, after: Option<&str>, limit: usize) -> Result<Vec<User>, Whatever> {
+        let users = self.users.read().await;
+        let mut users = users
+            .values()
+            .filter(|u| after.is_none_or(|after| u.zid.as_str() > after))
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.zid.cmp(&b.zid));
+
+        Ok(users.into_iter().take(limit).cloned().collect())
+    }
+
+    async fn delete_by_zid(&self, zid: &str) -> Result<(), Whatever> {
+        self.users.write().await.remove(zid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryUserStore;
+    use crate::auth::tests::check_round_trip;
+
+    #[tokio::test]
+    async fn round_trips_users() { check_round_trip(&MemoryUserStore::default()).await; }
+}
@@ -1,183 +1,651 @@
-use std::result::Result;
-
-use futures::StreamExt;
-use mongodb::{
-    bson::doc,
-    options::{ClientOptions, IndexOptions, UpdateOptions},
-    Client,
-    IndexModel,
-};
 use serde::{Deserialize, Serialize};
 use snafu::{whatever, Whatever};
-use tracing::{error, instrument};
+use tokio::sync::Mutex;
+use tracing::{error, info, instrument, warn};
+
+use self::{
+    file::FileUserStore,
+    memory::MemoryUserStore,
+    mongo::MongoUserStore,
+    tokens::{lookup_key, secrets_match, TokenScope, UserToken},
+};
+use crate::config::{StorageBackend, StorageConfig};
+
+mod file;
+mod memory;
+mod mongo;
+pub(crate) mod tokens;
 
 /// Represents a student who has access to use the current relay server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct User {
-    pub(crate) zid:   String,
-    pub(crate) token: String,
+    pub(crate) zid:    String,
+    /// The user's tokens. Any one of them can be used to authenticate.
+    #[serde(default)]
+    pub(crate) tokens: Vec<UserToken>,
+    /// The user's token in plaintext, from before tokens were hashed. It is
+    /// moved into `tokens` the next time the user authenticates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) token:  Option<String>,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct UserManager {
-    db_client: mongodb::Client,
+impl User {
+    /// Whether any of the user's tokens accepts `token` for `scope`.
+    fn accepts(&self, token: &str, scope: TokenScope) -> bool {
+        let Some(accepted) = self.tokens.iter().find(|t| t.accepts(token, scope)) else {
+            return false;
+        };
+
+        if accepted.scope == TokenScope::Any {
+            warn!(
+                "{} used token {}, which has no scope; it should be reissued",
+                self.zid, accepted.id
+            );
+        }
+
+        true
+    }
+
+    /// Removes the token with the given id, returning whether it existed.
+    fn revoke(&mut self, id: &str) -> bool {
+        let count = self.tokens.len();
+        self.tokens.retain(|t| t.id != id);
+        self.tokens.len() != count
+    }
 }
 
-macro_rules! create_unique_index {
-    ($name:expr) => {
-        IndexModel::builder()
-            .keys(doc! { $name: 1 })
-            .options(IndexOptions::builder().unique(true).build())
-            .build()
-    };
+/// A backend that persists the users who have access to the relay.
+#[tonic::async_trait]
+pub(crate) trait UserStore: std::fmt::Debug + Send + Sync {
+    /// Gets the user with the given zid.
+    async fn get_by_zid(&self, zid: &str) -> Option<User>;
+
+    /// Gets every user with a hashed token whose lookup key is `key`.
+    async fn get_by_token_key(&self, key: &str) -> Vec<User>;
+
+    /// Gets the user with the given plaintext token, from before tokens were
+    /// hashed.
+    async fn get_by_plaintext_token(&self, token: &str) -> Option<User>;
+
+    /// Creates the user, or replaces the existing user with the same zid.
+    async fn upsert_user(&self, user: User) -> Result<(), Whatever>;
+
+    /// Lists up to `limit` users, ordered by zid, starting after the user with
+    /// the zid `after` if it is given.
+    async fn list_users(&self, after: Option<&str>, limit: usize) -> Result<Vec<User>, Whatever>;
+
+    /// Deletes the user with the given zid, if they exist.
+    async fn delete_by_zid(&self, zid: &str) -> Result<(), Whatever>;
 }
 
-impl UserManager {
-    #[instrument]
-    pub(crate) async fn new() -> Self {
-        let client_options = match ClientOptions::parse(
-            std::env::var("MONGODB_URI").expect("no MONGODB_URI was provided"),
-        )
-        .await
-        {
-            Ok(options) => options,
-            Err(e) => {
-                error!(
-                    "Failed to create parse mongodb client options. Is your string valid? {}",
-                    e
-                );
-                panic!()
-            },
-        };
+/// Provides access to the configured [`UserStore`].
+#[derive(Debug)]
+pub(crate) struct UserManager {
+    store:  Box<dyn UserStore>,
+    /// Held while a user is changed, so that concurrent changes to the same
+    /// user's tokens aren't lost. This only covers this server; the stores
+    /// don't support running several at once.
+    writes: Mutex<()>,
+}
 
-        let db_client = match Client::with_options(client_options) {
-            Ok(client) => client,
-            Err(e) => {
-                error!("Failed to create mongodb client. {}", e);
-                panic!()
+impl UserManager {
+    /// Creates the user store selected in the config.
+    #[instrument(skip(config))]
+    pub(crate) async fn new(config: &StorageConfig) -> Self {
+        let store: Box<dyn UserStore> = match config.backend {
+            StorageBackend::Mongodb => {
+                // the config is validated to have a uri for this backend
+                let uri = config.mongodb_uri.as_deref().unwrap_or_default();
+                Box::new(MongoUserStore::new(uri).await)
             },
-        };
-
-        // create indexes for the users collection
-        let collection = db_client.database("relay").collection::<User>("users");
-        // check if the indexes already exist
-        let indexes: Vec<IndexModel> = match collection.list_indexes(None).await {
-            Ok(indexes) => indexes.map(Result::unwrap).collect().await,
-            Err(e) => {
-                if e.to_string().contains("ns does not exist") {
-                    // the collection doesn't exist, so we need to create it
-                    match collection
-                        .create_indexes(
-                            vec![create_unique_index!("zid"), create_unique_index!("token")],
-                            None,
-                        )
-                        .await
-                    {
-                        Ok(_) => {},
-                        Err(e) => {
-                            error!("Failed to create mongodb indexes: {}", e);
-                            panic!()
-                        },
-                    };
-                } else {
-                    error!("Failed to list indexes. {}", e);
+            StorageBackend::Memory => Box::new(MemoryUserStore::default()),
+            StorageBackend::File => match FileUserStore::open(config.path.clone()).await {
+                Ok(store) => Box::new(store),
+                Err(e) => {
+                    error!("Failed to open the user store file. {}", e);
                     panic!()
-                }
-                error!("Failed to list indexes. {}", e);
-                panic!()
+                },
             },
         };
 
-        let indexes_to_create = vec!["zid", "token"];
-        for index in indexes_to_create {
-            if !indexes.iter().any(|i| i.keys.contains_key(index)) {
-                match collection
-                    .create_index(
-                        IndexModel::builder()
-                            .keys(doc! {index: 1})
-                            .options(IndexOptions::builder().unique(true).build())
-                            .build(),
-                        None,
-                    )
-                    .await
-                {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to create index. {}", e);
-                        panic!()
-                    },
-                }
-            }
+        info!("using the `{:?}` user store", config.backend);
+        Self::with_store(store)
+    }
+
+    fn with_store(store: Box<dyn UserStore>) -> Self {
+        Self {
+            store,
+            writes: Mutex::new(()),
         }
+    }
+}
 
-        Self { db_client }
+impl UserManager {
+    /// Gets the user with the given zid.
+    pub(crate) async fn get_by_zid(&self, zid: &str) -> Option<User> {
+        self.store.get_by_zid(zid).await
     }
 
-    fn get_users_collection(&self) -> mongodb::Collection<User> {
-        self.db_client.database("relay").collection("users")
+    /// Lists up to `limit` users, ordered by zid, starting after the user with
+    /// the zid `after` if it is given.
+    pub(crate) async fn list_users(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<User>, Whatever> {
+        self.store.list_users(after, limit).await
     }
 
+    /// Deletes the user with the given zid, if they exist.
     #[instrument]
-    pub(crate) async fn get_by_zid(&self, zid: &str) -> Option<User> {
-        let filter = doc! {"zid": zid};
-        let user = self.get_users_collection().find_one(filter, None).await;
-
-        match user {
-            Ok(Some(user)) => Some(user),
-            Ok(None) => None,
-            Err(e) => {
-                error!("[get_by_zid] error: {}", e);
-                None
-            },
+    pub(crate) async fn delete_user(&self, zid: &str) -> Result<(), Whatever> {
+        let _writes = self.writes.lock().await;
+        self.store.delete_by_zid(zid).await
+    }
+
+    /// Finds the user that `token` belongs to, if it can be used for `scope`.
+    #[instrument(skip(token))]
+    pub(crate) async fn authenticate(&self, token: &str, scope: TokenScope) -> Option<User> {
+        let candidates = self.store.get_by_token_key(&lookup_key(token)).await;
+        if let Some(user) = candidates.into_iter().find(|u| u.accepts(token, scope)) {
+            return Some(user);
         }
+
+        // fall back to users whose tokens haven't been hashed yet
+        let user = self.store.get_by_plaintext_token(token).await?;
+        self.verify_plaintext(user, token).await
     }
 
-    #[instrument]
-    pub(crate) async fn get_by_token(&self, token: &str) -> Option<User> {
-        let filter = doc! {"token": token};
-        let user = self.get_users_collection().find_one(filter, None).await;
-
-        match user {
-            Ok(Some(user)) => Some(user),
-            Ok(None) => None,
-            Err(e) => {
-                error!("[get_by_token] error: {}", e);
-                None
-            },
+    /// Checks that `token` belongs to the user with the given zid, and that it
+    /// can be used for `scope`.
+    #[instrument(skip(token))]
+    pub(crate) async fn verify(&self, zid: &str, token: &str, scope: TokenScope) -> Option<User> {
+        let user = self.store.get_by_zid(zid).await?;
+        if user.accepts(token, scope) {
+            return Some(user);
         }
+
+        self.verify_plaintext(user, token).await
+    }
+
+    /// Replaces all of the user's tokens with `token`, creating the user if
+    /// they don't exist. The token can be used for any scope.
+    #[instrument(skip(token))]
+    pub(crate) async fn set_token(&self, zid: String, token: &str) -> Result<(), Whatever> {
+        let _writes = self.writes.lock().await;
+        self.store
+            .upsert_user(User {
+                zid,
+                tokens: vec![UserToken::new(
+                    token,
+                    "manual".to_string(),
+                    TokenScope::Any,
+                    None,
+                )],
+                token: None,
+            })
+            .await
     }
 
+    /// Generates a new token for `scope`, creating the user if they don't
+    /// exist. If `replaces` is set, the token with that id is revoked.
+    ///
+    /// Returns the token, which is not stored anywhere, along with its details.
     #[instrument]
-    pub(crate) async fn upsert_user(&self, user: User) -> Result<(), Whatever> {
-        let collection = self.get_users_collection();
-        let result = collection
-            .update_one(
-                doc! {"zid": user.zid},
-                doc! {"$set": doc! {"token": user.token}},
-                Some(UpdateOptions::builder().upsert(true).build()),
-            )
-            .await;
+    pub(crate) async fn generate_token(
+        &self,
+        zid: &str,
+        label: String,
+        scope: TokenScope,
+        ttl_seconds: Option<u64>,
+        replaces: Option<&str>,
+    ) -> Result<(String, UserToken), Whatever> {
+        let _writes = self.writes.lock().await;
+        let mut user = self.store.get_by_zid(zid).await.unwrap_or_else(|| User {
+            zid:    zid.to_string(),
+            tokens: Vec::new(),
+            token:  None,
+        });
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                error!("[upsert_user] error: {}", e);
-                whatever!("failed to upsert: {}", e)
-            },
+        if let Some(id) = replaces {
+            if !user.revoke(id) {
+                whatever!("{} has no token with id {}", zid, id);
+            }
         }
+
+        let token = tokens::generate();
+        let info = UserToken::new(&token, label, scope, ttl_seconds);
+        user.tokens.push(info.clone());
+        self.store.upsert_user(user).await?;
+
+        Ok((token, info))
     }
 
+    /// Revokes one of the user's tokens.
     #[instrument]
-    pub(crate) async fn delete_by_zid(&self, zid: &str) -> Result<(), Whatever> {
-        let collection = self.get_users_collection();
-        let result = collection.delete_one(doc! {"zid": zid}, None).await;
-
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                error!("[delete_by_zid] error: {}", e);
-                whatever!("failed to delete: {}", e)
-            },
+    pub(crate) async fn revoke_token(&self, zid: &str, id: &str) -> Result<(), Whatever> {
+        let _writes = self.writes.lock().await;
+        let Some(mut user) = self.store.get_by_zid(zid).await else {
+            whatever!("{} does not exist", zid);
+        };
+
+        if !user.revoke(id) {
+            whatever!("{} has no token with id {}", zid, id);
         }
+
+        self.store.upsert_user(user).await
+    }
+
+    /// Checks a user's plaintext token against `token`, replacing it with a
+    /// hash if it matches.
+    async fn verify_plaintext(&self, user: User, token: &str) -> Option<User> {
+        if !secrets_match(user.token.as_deref()?, token) {
+            return None;
+        }
+
+        // the user may have changed since they were read, e.g. if the token was
+        // migrated by another request, so read them again before writing
+        let _writes = self.writes.lock().await;
+        let mut user = self.store.get_by_zid(&user.zid).await?;
+        if !user
+            .token
+            .as_deref()
+            .is_some_and(|t| secrets_match(t, token))
+        {
+            return user.accepts(token, TokenScope::Any).then_some(user);
+        }
+
+        user.tokens.push(UserToken::new(
+            token,
+            "migrated".to_string(),
+            TokenScope::Any,
+            None,
+        ));
+        user.token = None;
+        if let Err(e) = self.store.upsert_user(user.clone()).await {
+            // the user can still authenticate; migration will be retried next time
+            warn!("failed to migrate the token of {}: {}", user.zid, e);
+        }
+
+        Some(user)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    pub(crate) use super::memory::MemoryUserStore;
+    use super::{
+        tokens::{lookup_key, TokenScope, UserToken},
+        User,
+        UserManager,
+        UserStore,
+    };
+
+    /// Creates a user holding `token`.
+    pub(crate) fn user(zid: &str, token: &str) -> User {
+        User {
+            zid:    zid.to_string(),
+            tokens: vec![UserToken::new(
+                token,
+                "test".to_string(),
+                TokenScope::Client,
+                None,
+            )],
+            token:  None,
+        }
+    }
+
+    fn zids(users: &[User]) -> Vec<&str> { users.iter().map(|u| u.zid.as_str()).collect() }
+
+    /// Checks that users written to `store` can be read back in every way.
+    pub(super) async fn check_round_trip(store: &dyn UserStore) {
+        assert!(store.get_by_zid("z1111111").await.is_none());
+
+        let alice = user("z1111111", "alice-token");
+        let token_id = alice.tokens[0].id.clone();
+        store.upsert_user(alice).await.unwrap();
+        store
+            .upsert_user(User {
+                zid:    "z2222222".to_string(),
+                tokens: Vec::new(),
+                token:  Some("legacy-token".to_string()),
+            })
+            .await
+            .unwrap();
+
+        let alice = store.get_by_zid("z1111111").await.unwrap();
+        assert_eq!(alice.tokens[0].id, token_id);
+        assert_eq!(
+            zids(&store.get_by_token_key(&lookup_key("alice-token")).await),
+            ["z1111111"]
+        );
+        assert!(store
+            .get_by_token_key(&lookup_key("someone-else"))
+            .await
+            .is_empty());
+        assert_eq!(
+            store
+                .get_by_plaintext_token("legacy-token")
+                .await
+                .map(|u| u.zid),
+            Some("z2222222".to_string())
+        );
+
+        assert_eq!(
+            zids(&store.list_users(None, 10).await.unwrap()),
+            ["z1111111", "z2222222"]
+        );
+        assert_eq!(
+            zids(&store.list_users(Some("z1111111"), 10).await.unwrap()),
+            ["z2222222"]
+        );
+
+        // upserting replaces the whole user
+        store
+            .upsert_user(User {
+                tokens: Vec::new(),
+                ..alice
+            })
+            .await
+            .unwrap();
+        assert!(store
+            .get_by_token_key(&lookup_key("alice-token"))
+            .await
+            .is_empty());
+
+        store.delete_by_zid("z1111111").await.unwrap();
+        store.delete_by_zid("z9999999").await.unwrap();
+        assert!(store.get_by_zid("z1111111").await.is_none());
+        assert_eq!(
+            zids(&store.list_users(None, 10).await.unwrap()),
+            ["z2222222"]
+        );
+    }
+
+    pub(crate) fn manager() -> UserManager {
+        UserManager::with_store(Box::new(MemoryUserStore::default()))
+    }
+
+    fn legacy_user(zid: &str, token: &str) -> User {
+        User {
+            zid:    zid.to_string(),
+            tokens: Vec::new(),
+            token:  Some(token.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_plaintext_tokens_when_they_are_used() {
+        let manager = manager();
+        manager
+            .store
+            .upsert_user(legacy_user("z1111111", "legacy-token"))
+            .await
+            .unwrap();
+
+        assert!(manager
+            .authenticate("wrong-token", TokenScope::Client)
+            .await
+            .is_none());
+        let user = manager
+            .authenticate("legacy-token", TokenScope::Client)
+            .await
+            .unwrap();
+        assert_eq!(user.zid, "z1111111");
+
+        // the plaintext token is replaced by a hash that works for any scope
+        let stored = manager.get_by_zid("z1111111").await.unwrap();
+        assert!(stored.token.is_none());
+        assert_eq!(stored.tokens.len(), 1);
+        assert_eq!(stored.tokens[0].label, "migrated");
+        assert_eq!(stored.tokens[0].scope, TokenScope::Any);
+        assert!(manager
+            .store
+            .get_by_plaintext_token("legacy-token")
+            .await
+            .is_none());
+        assert_eq!(
+            zids(
+                &manager
+                    .store
+                    .get_by_token_key(&lookup_key("legacy-token"))
+                    .await
+            ),
+            ["z1111111"]
+        );
+
+        assert!(manager
+            .authenticate("legacy-token", TokenScope::Client)
+            .await
+            .is_some());
+        assert!(manager
+            .verify("z1111111", "legacy-token", TokenScope::Runner)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn migrates_plaintext_tokens_when_runners_log_in() {
+        let manager = manager();
+        manager
+            .store
+            .upsert_user(legacy_user("z1111111", "legacy-token"))
+            .await
+            .unwrap();
+
+        assert!(manager
+            .verify("z1111111", "wrong-token", TokenScope::Runner)
+            .await
+            .is_none());
+        assert!(manager
+            .verify("z2222222", "legacy-token", TokenScope::Runner)
+            .await
+            .is_none());
+        assert!(manager
+            .verify("z1111111", "legacy-token", TokenScope::Runner)
+            .await
+            .is_some());
+
+        let stored = manager.get_by_zid("z1111111").await.unwrap();
+        assert!(stored.token.is_none());
+        assert!(stored.tokens[0].accepts("legacy-token", TokenScope::Client));
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let manager = manager();
+        let (expired, _) = manager
+            .generate_token(
+                "z1111111",
+                "client".to_string(),
+                TokenScope::Client,
+                Some(0),
+                None,
+            )
+            .await
+            .unwrap();
+        let (current, _) = manager
+            .generate_token(
+                "z1111111",
+                "client".to_string(),
+                TokenScope::Client,
+                Some(30),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(manager
+            .authenticate(&expired, TokenScope::Client)
+            .await
+            .is_none());
+        assert!(manager
+            .authenticate(&current, TokenScope::Client)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let manager = manager();
+        let (revoked, info) = manager
+            .generate_token(
+                "z1111111",
+                "runner".to_string(),
+                TokenScope::Runner,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let (kept, _) = manager
+            .generate_token(
+                "z1111111",
+                "runner".to_string(),
+                TokenScope::Runner,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        manager.revoke_token("z1111111", &info.id).await.unwrap();
+        assert!(manager
+            .verify("z1111111", &revoked, TokenScope::Runner)
+            .await
+            .is_none());
+        assert!(manager
+            .verify("z1111111", &kept, TokenScope::Runner)
+            .await
+            .is_some());
+
+        assert!(manager.revoke_token("z1111111", &info.id).await.is_err());
+        assert!(manager.revoke_token("z2222222", &info.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rotating_a_token_revokes_the_old_one() {
+        let manager = manager();
+        let (old, info) = manager
+            .generate_token(
+                "z1111111",
+                "client".to_string(),
+                TokenScope::Client,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let (new, _) = manager
+            .generate_token(
+                "z1111111",
+                "client".to_string(),
+                TokenScope::Client,
+                None,
+                Some(&info.id),
+            )
+            .await
+            .unwrap();
+
+        assert!(manager
+            .authenticate(&old, TokenScope::Client)
+            .await
+            .is_none());
+        assert!(manager
+            .authenticate(&new, TokenScope::Client)
+            .await
+            .is_some());
+
+        // replacing a token that doesn't exist issues nothing
+        assert!(manager
+            .generate_token(
+                "z1111111",
+                "client".to_string(),
+                TokenScope::Client,
+                None,
+                Some(&info.id),
+            )
+            .await
+            .is_err());
+        assert_eq!(
+            manager.get_by_zid("z1111111").await.unwrap().tokens.len(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_token_changes_are_not_lost() {
+        let manager = std::sync::Arc::new(manager());
+        let generated = futures::future::join_all((0..50).map(|_| {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                manager
+                    .generate_token(
+                        "z1111111",
+                        "client".to_string(),
+                        TokenScope::Client,
+                        None,
+                        None,
+                    )
+                    .await
+                    .unwrap()
+            })
+        }))
+        .await;
+
+        assert_eq!(
+            manager.get_by_zid("z1111111").await.unwrap().tokens.len(),
+            50
+        );
+        for result in generated {
+            let (token, _) = result.unwrap();
+            assert!(manager
+                .authenticate(&token, TokenScope::Client)
+                .await
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_only_work_for_their_scope() {
+        let manager = manager();
+        let (runner, _) = manager
+            .generate_token(
+                "z1111111",
+                "runner".to_string(),
+                TokenScope::Runner,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let (client, _) = manager
+            .generate_token(
+                "z1111111",
+                "client".to_string(),
+                TokenScope::Client,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(manager
+            .authenticate(&runner, TokenScope::Client)
+            .await
+            .is_none());
+        assert!(manager
+            .verify("z1111111", &client, TokenScope::Runner)
+            .await
+            .is_none());
+
+        assert!(manager
+            .authenticate(&client, TokenScope::Client)
+            .await
+            .is_some());
+        assert!(manager
+            .verify("z1111111", &runner, TokenScope::Runner)
+            .await
+            .is_some());
     }
 }
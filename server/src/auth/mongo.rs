@@ -0,0 +1,234 @@
+use std::result::Result;
+
+use futures::StreamExt;
+use mongodb::{
+    bson::doc,
+    options::{ClientOptions, FindOptions, IndexOptions, ReplaceOptions},
+    Client,
+    IndexModel,
+};
+use snafu::{whatever, Whatever};
+use tracing::{error, instrument};
+
+use super::{User, UserStore};
+
+/// A [`UserStore`] backed by the `users` collection of a `MongoDB` database.
+#[derive(Debug, Clone)]
+pub(crate) struct MongoUserStore {
+    db_client: mongodb::Client,
+}
+
+macro_rules! create_index {
+    ($name:expr) => {
+        IndexModel::builder().keys(doc! { $name: 1 }).build()
+    };
+    ($name:expr, unique) => {
+        IndexModel::builder()
+            .keys(doc! { $name: 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build()
+    };
+    ($name:expr, unique, sparse) => {
+        IndexModel::builder()
+            .keys(doc! { $name: 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build()
+    };
+}
+
+impl MongoUserStore {
+    #[instrument(skip(uri))]
+    pub(crate) async fn new(uri: &str) -> Self {
+        let client_options = match ClientOptions::parse(uri).await {
+            Ok(options) => options,
+            Err(e) => {
+                error!(
+                    "Failed to create parse mongodb client options. Is your string valid? {}",
+                    e
+                );
+                panic!()
+            },
+        };
+
+        let db_client = match Client::with_options(client_options) {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to create mongodb client. {}", e);
+                panic!()
+            },
+        };
+
+        // create indexes for the users collection
+        let collection = db_client.database("relay").collection::<User>("users");
+        // check if the indexes already exist
+        let indexes: Vec<IndexModel> = match collection.list_indexes(None).await {
+            Ok(indexes) => indexes.map(Result::unwrap).collect().await,
+            // the collection doesn't exist yet, so every index needs to be created
+            Err(e) if e.to_string().contains("ns does not exist") => Vec::new(),
+            Err(e) => {
+                error!("Failed to list indexes. {}", e);
+                panic!()
+            },
+        };
+
+        // migrated users no longer have a plaintext `token`, so its index must be
+        // sparse for them not to collide with each other
+        let legacy_token_index = indexes.iter().find(|i| {
+            i.keys.contains_key("token")
+                && !i.options.as_ref().and_then(|o| o.sparse).unwrap_or(false)
+        });
+        if let Some(index) = legacy_token_index {
+            let name = index
+                .options
+                .as_ref()
+                .and_then(|o| o.name.clone())
+                .unwrap_or_else(|| "token_1".to_string());
+            if let Err(e) = collection.drop_index(name, None).await {
+                error!("Failed to drop the legacy token index. {}", e);
+                panic!()
+            }
+        }
+
+        let indexes_to_create = vec![
+            create_index!("zid", unique),
+            create_index!("token", unique, sparse),
+            create_index!("tokens.lookup"),
+        ];
+        for index in indexes_to_create {
+            let exists = indexes.iter().any(|i| {
+                i.keys == index.keys
+                    && i.options.as_ref().and_then(|o| o.sparse)
+                        == index.options.as_ref().and_then(|o| o.sparse)
+            });
+            if exists {
+                continue;
+            }
+
+            if let Err(e) = collection.create_index(index, None).await {
+                error!("Failed to create index. {}", e);
+                panic!()
+            }
+        }
+
+        Self { db_client }
+    }
+
+    fn get_users_collection(&self) -> mongodb::Collection<User> {
+        self.db_client.database("relay").collection("users")
+    }
+}
+
+#[tonic::async_trait]
+impl UserStore for MongoUserStore {
+    #[instrument]
+    async fn get_by_zid(&self, zid: &str) -> Option<User> {
+        let filter = doc! {"zid": zid};
+        let user = self.get_users_collection().find_one(filter, None).await;
+
+        match user {
+            Ok(Some(user)) => Some(user),
+            Ok(None) => None,
+            Err(e) => {
+                error!("[get_by_zid] error: {}", e);
+                None
+            },
+        }
+    }
+
+    #[instrument(skip(key))]
+    async fn get_by_token_key(&self, key: &str) -> Vec<User> {
+        let filter = doc! {"tokens.lookup": key};
+        let users = match self.get_users_collection().find(filter, None).await {
+            Ok(cursor) => cursor.collect::<Vec<_>>().await,
+            Err(e) => {
+                error!("[get_by_token_key] error: {}", e);
+                return Vec::new();
+            },
+        };
+
+        users
+            .into_iter()
+            .filter_map(|user| match user {
+                Ok(user) => Some(user),
+                Err(e) => {
+                    error!("[get_by_token_key] error: {}", e);
+                    None
+                },
+            })
+            .collect()
+    }
+
+    #[instrument(skip(token))]
+    async fn get_by_plaintext_token(&self, token: &str) -> Option<User> {
+        let filter = doc! {"token": token};
+        let user = self.get_users_collection().find_one(filter, None).await;
+
+        match user {
+            Ok(Some(user)) => Some(user),
+            Ok(None) => None,
+            Err(e) => {
+                error!("[get_by_plaintext_token] error: {}", e);
+                None
+            },
+        }
+    }
+
+    #[instrument(skip(user), fields(zid = %user.zid))]
+    async fn upsert_user(&self, user: User) -> Result<(), Whatever> {
+        let collection = self.get_users_collection();
+        let result = collection
+            .replace_one(
+                doc! {"zid": &user.zid},
+                &user,
+                Some(ReplaceOptions::builder().upsert(true).build()),
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("[upsert_user] error: {}", e);
+                whatever!("failed to upsert: {}", e)
+            },
+        }
+    }
+
+    #[instrument]
+    async fn list_users(&self, after: Option<&str>, limit: usize) -> Result<Vec<User>, Whatever> {
+        let filter = after.map(|after| doc! {"zid": {"$gt": after}});
+        let options = FindOptions::builder()
+            .sort(doc! {"zid": 1})
+            .limit(i64::try_from(limit).unwrap_or(i64::MAX))
+            .build();
+
+        let users = match self.get_users_collection().find(filter, options).await {
+            Ok(cursor) => cursor.collect::<Vec<_>>().await,
+            Err(e) => {
+                error!("[list_users] error: {}", e);
+                whatever!("failed to list users: {}", e)
+            },
+        };
+
+        match users.into_iter().collect() {
+            Ok(users) => Ok(users),
+            Err(e) => {
+                error!("[list_users] error: {}", e);
+                whatever!("failed to list users: {}", e)
+            },
+        }
+    }
+
+    #[instrument]
+    async fn delete_by_zid(&self, zid: &str) -> Result<(), Whatever> {
+        let collection = self.get_users_collection();
+        let result = collection.delete_one(doc! {"zid": zid}, None).await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("[delete_by_zid] error: {}", e);
+                whatever!("failed to delete: {}", e)
+            },
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::SystemTime;
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::client_manager::unix_seconds;
+
+/// The number of random bytes each token's hash is salted with.
+const SALT_LENGTH: usize = 16;
+
+/// The number of random bytes in a generated token.
+const TOKEN_LENGTH: usize = 32;
+
+/// What a token can be used for. A token with one scope is rejected when used
+/// for the other, so a leaked runner token can't be used to run commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TokenScope {
+    /// Running commands through the `gRPC` api.
+    Client,
+    /// Connecting a runner over the websocket.
+    Runner,
+    /// Both. Only held by tokens created before scopes existed, which should be
+    /// revoked and reissued.
+    #[default]
+    Any,
+}
+
+/// One of a user's tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UserToken {
+    /// Identifies the token, so that it can be revoked.
+    pub(crate) id:         String,
+    /// A description of what the token is for, e.g. `runner` or `client`.
+    pub(crate) label:      String,
+    /// What the token can be used for.
+    #[serde(default)]
+    pub(crate) scope:      TokenScope,
+    /// When the token was created, in seconds since the unix epoch.
+    pub(crate) created_at: i64,
+    /// When the token expires, in seconds since the unix epoch, if ever.
+    pub(crate) expires_at: Option<i64>,
+    #[serde(flatten)]
+    pub(crate) hash:       HashedToken,
+}
+
+impl UserToken {
+    /// Creates a token from `token` that expires after `ttl_seconds`, if set.
+    pub(crate) fn new(
+        token: &str,
+        label: String,
+        scope: TokenScope,
+        ttl_seconds: Option<u64>,
+    ) -> Self {
+        let created_at = unix_seconds(SystemTime::now());
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            label,
+            scope,
+            created_at,
+            expires_at: ttl_seconds
+                .map(|ttl| created_at.saturating_add(i64::try_from(ttl).unwrap_or(i64::MAX))),
+            hash: HashedToken::new(token),
+        }
+    }
+
+    /// Whether the token has expired.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at <= unix_seconds(SystemTime::now()))
+    }
+
+    /// Checks whether `token` is this token, that it can be used for `scope`,
+    /// and that it hasn't expired.
+    pub(crate) fn accepts(&self, token: &str, scope: TokenScope) -> bool {
+        (self.scope == scope || self.scope == TokenScope::Any)
+            && !self.is_expired()
+            && self.hash.verify(token)
+    }
+}
+
+/// A token, stored as a salted hash. The token itself is never stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct HashedToken {
+    /// The token's [`lookup_key`], used to find it without scanning every
+    /// user.
+    pub(crate) lookup: String,
+    /// The hex-encoded salt.
+    pub(crate) salt:   String,
+    /// The hex-encoded SHA-256 hash of the salt followed by the token.
+    pub(crate) hash:   String,
+}
+
+impl HashedToken {
+    /// Hashes `token` with a new random salt.
+    pub(crate) fn new(token: &str) -> Self {
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Self {
+            lookup: lookup_key(token),
+            salt:   hex::encode(salt),
+            hash:   hex::encode(hash(&salt, token)),
+        }
+    }
+
+    /// Checks whether `token` is the token this hash was created from, in
+    /// constant time.
+    pub(crate) fn verify(&self, token: &str) -> bool {
+        let (Ok(salt), Ok(expected)) = (hex::decode(&self.salt), hex::decode(&self.hash)) else {
+            return false;
+        };
+
+        hash(&salt, token).ct_eq(&expected).into()
+    }
+}
+
+/// Generates a new random token.
+pub(crate) fn generate() -> String {
+    let mut token = [0u8; TOKEN_LENGTH];
+    rand::thread_rng().fill_bytes(&mut token);
+    hex::encode(token)
+}
+
+/// Returns the key `token` is looked up by: the hex-encoded SHA-256 hash of
+/// the whole token, without a salt, so that the same token always has the same
+/// key. Unlike a prefix of the token, it can't be used to guess any of it.
+pub(crate) fn lookup_key(token: &str) -> String { hex::encode(hash(&[], token)) }
+
+/// Compares two secrets in constant time.
+pub(crate) fn secrets_match(a: &str, b: &str) -> bool { a.as_bytes().ct_eq(b.as_bytes()).into() }
+
+fn hash(salt: &[u8], token: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(token.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup_key, HashedToken, TokenScope, UserToken};
+
+    #[test]
+    fn hashed_tokens_only_verify_their_own_token() {
+        let hashed = HashedToken::new("correct-token");
+
+        assert!(hashed.verify("correct-token"));
+        for wrong in ["", "correct", "correct-token ", "Correct-token"] {
+            assert!(!hashed.verify(wrong), "{wrong}");
+        }
+
+        let corrupt = HashedToken {
+            salt: "not hex".to_string(),
+            ..hashed
+        };
+        assert!(!corrupt.verify("correct-token"));
+    }
+
+    #[test]
+    fn hashed_tokens_are_salted_but_share_a_lookup_key() {
+        let a = HashedToken::new("token");
+        let b = HashedToken::new("token");
+
+        assert_ne!(a.salt, b.salt);
+        assert_ne!(a.hash, b.hash);
+        assert_eq!(a.lookup, b.lookup);
+        assert_eq!(a.lookup, lookup_key("token"));
+        assert_ne!(lookup_key("token"), lookup_key("token2"));
+    }
+
+    #[test]
+    fn lookup_keys_do_not_contain_the_token() {
+        for token in ["ab", "abcdefgh", "0123456789abcdef0123456789abcdef"] {
+            let key = lookup_key(token);
+            assert_eq!(key.len(), 64);
+            assert!(!key.contains(token), "{token}");
+        }
+    }
+
+    #[test]
+    fn tokens_are_only_accepted_for_their_scope() {
+        let client = UserToken::new("token", "client".to_string(), TokenScope::Client, None);
+        assert!(client.accepts("token", TokenScope::Client));
+        assert!(!client.accepts("token", TokenScope::Runner));
+        assert!(!client.accepts("other", TokenScope::Client));
+
+        let runner = UserToken::new("token", "runner".to_string(), TokenScope::Runner, None);
+        assert!(runner.accepts("token", TokenScope::Runner));
+        assert!(!runner.accepts("token", TokenScope::Client));
+
+        let any = UserToken::new("token", "legacy".to_string(), TokenScope::Any, None);
+        assert!(any.accepts("token", TokenScope::Client));
+        assert!(any.accepts("token", TokenScope::Runner));
+    }
+
+    #[test]
+    fn expired_tokens_are_not_accepted() {
+        let mut token = UserToken::new("token", "client".to_string(), TokenScope::Client, Some(30));
+        assert!(!token.is_expired());
+        assert!(token.accepts("token", TokenScope::Client));
+
+        token.expires_at = Some(token.created_at - 1);
+        assert!(token.is_expired());
+        assert!(!token.accepts("token", TokenScope::Client));
+    }
+}
@@ -1,16 +1,27 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use futures::{
+    channel::mpsc::{unbounded, UnboundedSender},
+    Stream,
+    StreamExt,
+};
 use snafu::Snafu;
-use tokio::sync::RwLock;
-use tracing::{error, instrument};
+use tokio::sync::{oneshot, RwLock};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tracing::{debug, error, instrument, warn};
 
-use self::tasks::TaskList;
+use self::tasks::{PendingTask, TaskList, TaskResult};
 use crate::{
     relay::{
-        core::{CommandRequest, CommandResponse},
-        ws_extensions::{socket_frame::Data, SocketFrame, TaskRequest},
+        admin::RunnerInfo,
+        core::{CommandRequest, CommandResponse, OutputChunk},
+        ws_extensions::{socket_frame::Data, SocketFrame, TaskCancel, TaskRequest},
     },
-    ws::PeerMap,
+    ws::{heartbeat::Heartbeat, PeerMap},
 };
 
 /// The manager that contains all peer data, handles message routing, and
@@ -18,17 +29,82 @@ use crate::{
 #[derive(Debug, Clone)]
 pub(crate) struct ClientManager {
     /// The map of peers.
-    pub(crate) peers: PeerMap,
+    pub(crate) peers:            PeerMap,
     /// The list of tasks.
-    pub(crate) tasks: TaskList,
+    pub(crate) tasks:            TaskList,
+    /// The longest a task may run for before the runner kills it.
+    pub(crate) max_task_timeout: Duration,
+    /// How runners are checked for signs of life.
+    pub(crate) heartbeat:        Heartbeat,
+}
+
+/// How much longer than a task's timeout the server waits for the runner to
+/// report back, before giving up on the task itself.
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// A task that has been sent to a runner. The task is cancelled if this is
+/// dropped before its result arrives.
+struct DispatchedTask {
+    result: oneshot::Receiver<TaskResult>,
+    guard:  CancelOnDrop,
+}
+
+/// Something that happened to a task whose output is streamed.
+#[derive(Debug)]
+pub(crate) enum TaskEvent {
+    /// The task produced some output.
+    Output(OutputChunk),
+    /// The task finished, or was given up on. This is always the last event.
+    Finished(TaskResult),
+}
+
+/// Cancels a task if dropped while armed, i.e. if whoever was waiting on the
+/// task's result goes away before it arrives.
+struct CancelOnDrop {
+    manager: ClientManager,
+    id:      String,
+    zid:     String,
+    armed:   bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let manager = self.manager.clone();
+        let id = std::mem::take(&mut self.id);
+        let zid = std::mem::take(&mut self.zid);
+        tokio::spawn(async move {
+            debug!("caller went away; cancelling task {}", id);
+            if let Err(e) = manager.cancel_task(&zid, &id).await {
+                debug!("failed to cancel task {}: {}", id, e);
+            }
+        });
+    }
 }
 
 pub(crate) mod tasks;
 
+/// Converts a time into seconds since the unix epoch.
+pub(crate) fn unix_seconds(time: SystemTime) -> i64 {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    i64::try_from(elapsed.as_secs()).unwrap_or(i64::MAX)
+}
+
 #[derive(Debug, Snafu)]
 pub(crate) enum ClientManagerError {
     #[snafu(display("no active runner connected"))]
     NoRunner,
+    #[snafu(display("the runner did not report back within the task's timeout"))]
+    TimedOut,
+    #[snafu(display("no such task"))]
+    NoTask,
+    #[snafu(display("the runner disconnected before the task finished"))]
+    RunnerDisconnected,
+    #[snafu(display("the runner sent an invalid response"))]
+    InvalidResponse,
 }
 
 macro_rules! get_peer_by_zid {
@@ -44,49 +120,349 @@ macro_rules! get_peer_by_zid {
 }
 
 impl ClientManager {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_task_timeout: Duration, heartbeat: Heartbeat) -> Self {
         Self {
             peers: Arc::new(RwLock::new(HashMap::new())),
             tasks: TaskList::new(),
+            max_task_timeout,
+            heartbeat,
         }
     }
 
+    /// Sends a task to the runner belonging to `zid` and waits for its result.
     #[instrument]
     pub(crate) async fn forward_task(
         &self,
         zid: &str,
         task: CommandRequest,
     ) -> Result<CommandResponse, ClientManagerError> {
+        let dispatched = self.dispatch_task(zid, task, None).await?;
+        Self::wait_for_result(dispatched).await
+    }
+
+    /// Sends a task to the runner belonging to `zid`, asking it to stream its
+    /// output back as it is produced.
+    ///
+    /// Returns the task's id, and a stream of each chunk of output followed by
+    /// the final result. The task is cancelled if the stream is dropped before
+    /// the result arrives.
+    #[instrument]
+    pub(crate) async fn stream_task(
+        &self,
+        zid: &str,
+        task: CommandRequest,
+    ) -> Result<(String, impl Stream<Item = TaskEvent>), ClientManagerError> {
+        let (output_tx, output_rx) = unbounded();
+        let dispatched = self.dispatch_task(zid, task, Some(output_tx)).await?;
+
+        // the dispatched task is owned by the stream from the start, so dropping the
+        // stream at any point cancels the task
+        let id = dispatched.guard.id.clone();
+        let result = futures::stream::once(async move {
+            TaskEvent::Finished(Self::wait_for_result(dispatched).await)
+        });
+
+        Ok((id, output_rx.map(TaskEvent::Output).chain(result)))
+    }
+
+    /// Asks the runner executing a task to cancel it. The task's result, with
+    /// status `CANCELLED`, is delivered as usual once the runner has killed it.
+    ///
+    /// Only the user who requested the task may cancel it.
+    #[instrument]
+    pub(crate) async fn cancel_task(&self, zid: &str, id: &str) -> Result<(), ClientManagerError> {
+        let (owner, runner) = match self.tasks.lookup(id).await {
+            Some((owner, runner)) if owner == zid => (owner, runner),
+            _ => return Err(ClientManagerError::NoTask),
+        };
+
+        let peers = self.peers.read().await;
+        let Some(peer) = peers.get(&runner) else {
+            warn!("runner for task {} ({}) is no longer connected", id, owner);
+            return Err(ClientManagerError::NoRunner);
+        };
+
+        peer.send_socket_frame(&SocketFrame {
+            data: Some(Data::TaskCancel(TaskCancel { id: id.to_string() })),
+        })
+        .map_err(|_| ClientManagerError::RunnerDisconnected)?;
+
+        self.tasks.mark_cancelling(id).await;
+        Ok(())
+    }
+
+    /// Closes the connection of every runner belonging to `zid`.
+    #[instrument]
+    pub(crate) async fn disconnect_runner(&self, zid: &str) -> Result<(), ClientManagerError> {
+        let peers = self.peers.read().await;
+        let runners = peers
+            .values()
+            .filter(|peer| peer.data.as_ref().is_some_and(|data| data.username == zid))
+            .collect::<Vec<_>>();
+        if runners.is_empty() {
+            return Err(ClientManagerError::NoRunner);
+        }
+
+        for runner in runners {
+            runner.close(CloseCode::Normal, "disconnected by an admin");
+        }
+
+        Ok(())
+    }
+
+    /// Describes every registered runner, in the order they connected.
+    pub(crate) async fn list_runners(&self) -> Vec<RunnerInfo> {
+        let peers = self.peers.read().await;
+        let mut runners = peers
+            .iter()
+            .filter_map(|(address, peer)| {
+                let data = peer.data.as_ref()?;
+                Some((
+                    peer.connected_at,
+                    RunnerInfo {
+                        zid:          data.username.clone(),
+                        address:      address.to_string(),
+                        connected_at: unix_seconds(peer.connected_at),
+                        version:      data.version.clone(),
+                        last_seen_at: unix_seconds(peer.last_seen()),
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+        runners.sort_by_key(|(connected_at, _)| *connected_at);
+
+        runners.into_iter().map(|(_, runner)| runner).collect()
+    }
+
+    /// Registers a new task and sends it to the runner belonging to `zid`.
+    async fn dispatch_task(
+        &self,
+        zid: &str,
+        mut task: CommandRequest,
+        output: Option<UnboundedSender<OutputChunk>>,
+    ) -> Result<DispatchedTask, ClientManagerError> {
         // first find the specific peer to send the message to
         let peer_map = self.peers.read().await;
         let peer = get_peer_by_zid!(zid, peer_map);
 
+        // cap the timeout so that no task can run indefinitely
+        let max_timeout = self.max_task_timeout.as_secs();
+        let timeout = match u64::from(task.timeout_seconds) {
+            0 => max_timeout,
+            t => t.min(max_timeout),
+        };
+        task.timeout_seconds = u32::try_from(timeout).unwrap_or(u32::MAX);
+
         // spawn a new oneshot channel for receiving the response
-        let (tx, rx) = tokio::sync::oneshot::channel::<Option<CommandResponse>>();
+        let (tx, rx) = oneshot::channel::<TaskResult>();
 
         // create a new task and add it to the list
         let task_id = uuid::Uuid::new_v4().to_string();
-        self.tasks.add_task(task_id.clone(), tx).await;
+        let stream_output = output.is_some();
+        self.tasks
+            .add_task(
+                task_id.clone(),
+                PendingTask {
+                    zid: zid.to_string(),
+                    runner: *peer.0,
+                    result: tx,
+                    output,
+                    command: task.command.clone(),
+                    arguments: task.arguments.clone(),
+                    started_at: SystemTime::now(),
+                    cancelling: false,
+                },
+            )
+            .await;
 
         let send_frame = SocketFrame {
             data: Some(Data::TaskRequest(TaskRequest {
-                id:      task_id,
+                id: task_id.clone(),
                 command: Some(task),
+                stream_output,
             })),
         };
 
         // send the task to the peer
-        peer.1.send_socket_frame(&send_frame);
+        if let Err(e) = peer.1.send_socket_frame(&send_frame) {
+            error!("failed to send task to runner: {}", e);
+            self.tasks.remove_task(&task_id).await;
+            return Err(ClientManagerError::RunnerDisconnected);
+        }
+
+        // give up on the task if the runner never reports back. the runner kills the
+        // task once it times out, so it should always report back before this
+        let manager = self.clone();
+        let (id, owner) = (task_id.clone(), zid.to_string());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(timeout) + TIMEOUT_GRACE_PERIOD).await;
+            manager.expire_task(&owner, &id).await;
+        });
+
+        Ok(DispatchedTask {
+            result: rx,
+            guard:  CancelOnDrop {
+                manager: self.clone(),
+                id:      task_id,
+                zid:     zid.to_string(),
+                armed:   true,
+            },
+        })
+    }
 
-        // wait for the response
-        let result = rx.await.unwrap();
+    /// Gives up on a task whose runner hasn't reported back, failing it with
+    /// `TimedOut`. Does nothing if the task has already finished.
+    async fn expire_task(&self, zid: &str, id: &str) {
+        if self.tasks.lookup(id).await.is_none() {
+            return;
+        }
 
-        // convert the core message into a auto test submission response
-        if let Some(result) = result {
-            Ok(result)
-        } else {
-            error!("received unexpected response type");
-            panic!();
+        warn!("runner did not report back for task {}", id);
+        // make one last attempt at stopping the task before forgetting about it
+        if let Err(e) = self.cancel_task(zid, id).await {
+            debug!("failed to cancel task {}: {}", id, e);
         }
+        self.tasks.fail_task(id, ClientManagerError::TimedOut).await;
+    }
+
+    /// Waits for the runner to report the result of a dispatched task.
+    ///
+    /// Tasks whose runner doesn't report back in time are failed with
+    /// `TimedOut` when they are dispatched, so this always finishes.
+    async fn wait_for_result(
+        mut task: DispatchedTask,
+    ) -> Result<CommandResponse, ClientManagerError> {
+        let result = (&mut task.result).await;
+        task.guard.armed = false;
+
+        // the sender is only ever dropped without sending if the task was forgotten
+        // about
+        result.unwrap_or(Err(ClientManagerError::RunnerDisconnected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{
+        channel::mpsc::{unbounded, UnboundedReceiver},
+        StreamExt,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{ClientManager, ClientManagerError, TaskEvent};
+    use crate::{
+        relay::{
+            core::{CommandRequest, OutputChunk},
+            ws_extensions::{socket_frame::Data, SocketFrame, TaskOutput},
+        },
+        ws::{heartbeat::Heartbeat, models::Peer},
+    };
+
+    const ZID: &str = "z5555555";
+
+    /// Creates a manager with a runner connected for [`ZID`], returning the
+    /// messages sent to the runner.
+    async fn manager_with_runner() -> (ClientManager, UnboundedReceiver<Message>) {
+        let manager = ClientManager::new(
+            Duration::from_secs(30),
+            Heartbeat {
+                interval:   Duration::from_secs(15),
+                max_missed: 3,
+            },
+        );
+
+        let (tx, rx) = unbounded();
+        let mut peer = Peer::new(tx);
+        peer.register(ZID.to_string(), "test".to_string());
+        manager
+            .peers
+            .write()
+            .await
+            .insert("127.0.0.1:1".parse().unwrap(), peer);
+
+        (manager, rx)
+    }
+
+    /// Waits for the next frame sent to the runner.
+    async fn next_frame(runner: &mut UnboundedReceiver<Message>) -> Data {
+        let message = tokio::time::timeout(Duration::from_secs(5), runner.next())
+            .await
+            .expect("nothing was sent to the runner")
+            .unwrap();
+        let Message::Binary(data) = message else {
+            panic!("unexpected message: {message:?}");
+        };
+
+        <SocketFrame as prost::Message>::decode(data.as_slice())
+            .unwrap()
+            .data
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dropping_a_task_stream_cancels_the_task() {
+        let (manager, mut runner) = manager_with_runner().await;
+        let (id, events) = manager
+            .stream_task(ZID, CommandRequest::default())
+            .await
+            .unwrap();
+        let mut events = Box::pin(events);
+
+        assert!(matches!(next_frame(&mut runner).await, Data::TaskRequest(r) if r.id == id));
+
+        // the client goes away partway through the output
+        manager
+            .tasks
+            .forward_output(TaskOutput {
+                id:    id.clone(),
+                chunk: Some(OutputChunk::default()),
+            })
+            .await;
+        assert!(matches!(events.next().await, Some(TaskEvent::Output(_))));
+        drop(events);
+
+        assert!(matches!(next_frame(&mut runner).await, Data::TaskCancel(c) if c.id == id));
+    }
+
+    #[tokio::test]
+    async fn dropping_an_unpolled_task_stream_cancels_the_task() {
+        let (manager, mut runner) = manager_with_runner().await;
+        let (id, events) = manager
+            .stream_task(ZID, CommandRequest::default())
+            .await
+            .unwrap();
+
+        assert!(matches!(next_frame(&mut runner).await, Data::TaskRequest(r) if r.id == id));
+        drop(events);
+
+        assert!(matches!(next_frame(&mut runner).await, Data::TaskCancel(c) if c.id == id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_streamed_tasks_the_runner_never_reports_back_on() {
+        let (manager, mut runner) = manager_with_runner().await;
+        let (id, events) = manager
+            .stream_task(
+                ZID,
+                CommandRequest {
+                    timeout_seconds: 5,
+                    ..CommandRequest::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        // the runner stays connected, but never sends any output or a result
+        let events = events.collect::<Vec<_>>().await;
+        assert!(matches!(
+            events.as_slice(),
+            [TaskEvent::Finished(Err(ClientManagerError::TimedOut))]
+        ));
+
+        assert!(matches!(next_frame(&mut runner).await, Data::TaskRequest(r) if r.id == id));
+        assert!(matches!(next_frame(&mut runner).await, Data::TaskCancel(c) if c.id == id));
+        assert!(manager.tasks.list().await.is_empty());
     }
 }
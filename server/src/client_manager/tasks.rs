@@ -1,13 +1,42 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::SystemTime};
 
+use futures::channel::mpsc::UnboundedSender;
 use tokio::sync::{oneshot::Sender, Mutex};
-use tracing::{debug, error, instrument};
+use tracing::{debug, error, instrument, warn};
 
-use crate::relay::{core::CommandResponse, ws_extensions::TaskResponse};
+use super::{unix_seconds, ClientManagerError};
+use crate::relay::{
+    admin::{TaskInfo, TaskState},
+    core::{CommandResponse, OutputChunk},
+    ws_extensions::{TaskOutput, TaskResponse},
+};
+
+/// The final result of a task, as delivered to whoever is waiting on it.
+pub(crate) type TaskResult = Result<CommandResponse, ClientManagerError>;
+
+/// A task that has been sent to a runner and is awaiting its result.
+#[derive(Debug)]
+pub(crate) struct PendingTask {
+    /// The zid of the user who requested the task.
+    pub(crate) zid:        String,
+    /// The address of the runner executing the task.
+    pub(crate) runner:     SocketAddr,
+    /// The channel that receives the final result of the task.
+    pub(crate) result:     Sender<TaskResult>,
+    /// The channel that receives output chunks, if the output is streamed.
+    pub(crate) output:     Option<UnboundedSender<OutputChunk>>,
+    /// The command being run, and its arguments.
+    pub(crate) command:    String,
+    pub(crate) arguments:  Vec<String>,
+    /// When the task was sent to the runner.
+    pub(crate) started_at: SystemTime,
+    /// Whether the task has been asked to cancel.
+    pub(crate) cancelling: bool,
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct TaskList {
-    pub(crate) tasks: Arc<Mutex<HashMap<String, Sender<Option<CommandResponse>>>>>,
+    pub(crate) tasks: Arc<Mutex<HashMap<String, PendingTask>>>,
 }
 
 impl TaskList {
@@ -17,32 +46,133 @@ impl TaskList {
         }
     }
 
-    pub(crate) async fn add_task(&self, id: String, channel: Sender<Option<CommandResponse>>) {
-        self.tasks.lock().await.insert(id, channel);
+    pub(crate) async fn add_task(&self, id: String, task: PendingTask) {
+        self.tasks.lock().await.insert(id, task);
+    }
+
+    /// Returns the zid of the task's owner and the address of the runner
+    /// executing it.
+    pub(crate) async fn lookup(&self, id: &str) -> Option<(String, SocketAddr)> {
+        self.tasks
+            .lock()
+            .await
+            .get(id)
+            .map(|task| (task.zid.clone(), task.runner))
+    }
+
+    /// Records that the task has been asked to cancel.
+    pub(crate) async fn mark_cancelling(&self, id: &str) {
+        if let Some(task) = self.tasks.lock().await.get_mut(id) {
+            task.cancelling = true;
+        }
+    }
+
+    /// Describes every task that is awaiting its result, oldest first.
+    pub(crate) async fn list(&self) -> Vec<TaskInfo> {
+        let tasks = self.tasks.lock().await;
+        let mut tasks = tasks.iter().collect::<Vec<_>>();
+        tasks.sort_by_key(|(_, task)| task.started_at);
+
+        tasks
+            .into_iter()
+            .map(|(id, task)| {
+                let state = if task.cancelling {
+                    TaskState::Cancelling
+                } else {
+                    TaskState::Running
+                };
+
+                TaskInfo {
+                    id:         id.clone(),
+                    zid:        task.zid.clone(),
+                    command:    task.command.clone(),
+                    arguments:  task.arguments.clone(),
+                    started_at: unix_seconds(task.started_at),
+                    state:      state.into(),
+                }
+            })
+            .collect()
+    }
+
+    /// Removes a task from the list and fails it with `error`. Its output
+    /// channel, if any, is closed.
+    #[instrument]
+    pub(crate) async fn fail_task(&self, id: &str, error: ClientManagerError) {
+        let Some(task) = self.tasks.lock().await.remove(id) else {
+            return;
+        };
+
+        // the caller may have already gone away
+        let _ = task.result.send(Err(error));
+    }
+
+    /// Removes a task from the list without completing it.
+    pub(crate) async fn remove_task(&self, id: &str) { self.tasks.lock().await.remove(id); }
+
+    /// Forwards a chunk of output to the task's output channel.
+    #[instrument]
+    pub(crate) async fn forward_output(&self, output: TaskOutput) {
+        let tasks = self.tasks.lock().await;
+        let Some(task) = tasks.get(&output.id) else {
+            warn!("received output for a task that doesn't exist");
+            return;
+        };
+
+        match (&task.output, output.chunk) {
+            (Some(chan), Some(chunk)) => {
+                if let Err(e) = chan.unbounded_send(chunk) {
+                    // the caller has gone away; the result will be dropped when it arrives
+                    debug!("failed to send output to task: {:?}", e);
+                }
+            },
+            (None, _) => warn!("received output for a task that isn't streamed"),
+            (_, None) => error!("task output chunk was None"),
+        }
     }
 
     /// Removes a task from the list and sends the result to the task's channel.
     #[instrument]
     pub(crate) async fn complete_task(&self, result: TaskResponse) {
         debug!("completing task: {}", result.id);
-        let chan = self
-            .tasks
-            .lock()
-            .await
-            .remove(&result.id)
-            .unwrap_or_else(|| {
-                error!("attempted to complete a task that doesn't exist");
-                panic!();
-            });
+        let Some(task) = self.tasks.lock().await.remove(&result.id) else {
+            // the task may have already been given up on
+            warn!("attempted to complete a task that doesn't exist");
+            return;
+        };
 
-        if result.response.is_none() {
+        let response = result.response.ok_or_else(|| {
             // this shouldn't happen if the runner is valid
             error!("task response was None");
-        }
+            ClientManagerError::InvalidResponse
+        });
 
         debug!("sending task result: {}", result.id);
-        if let Err(e) = chan.send(result.response) {
+        if let Err(e) = task.result.send(response) {
             error!("failed to send message to task: {:?}", e);
         }
     }
+
+    /// Fails every task being executed by the runner at `runner`, e.g. because
+    /// it has disconnected.
+    #[instrument]
+    pub(crate) async fn fail_runner_tasks(&self, runner: SocketAddr) {
+        let mut tasks = self.tasks.lock().await;
+        let ids = tasks
+            .iter()
+            .filter(|(_, task)| task.runner == runner)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in ids {
+            let Some(task) = tasks.remove(&id) else {
+                continue;
+            };
+
+            warn!("failing task {} as its runner disconnected", id);
+            // the caller may have already gone away
+            let _ = task
+                .result
+                .send(Err(ClientManagerError::RunnerDisconnected));
+        }
+    }
 }
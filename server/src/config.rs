@@ -0,0 +1,522 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use snafu::{ensure, ResultExt, Snafu};
+use tracing_subscriber::EnvFilter;
+
+/// The `VLab` relay server. Settings are read from the config file, if one is
+/// given, and can be overridden with environment variables or flags.
+#[derive(Parser)]
+#[clap(name = "vlab relay server", version, about, long_about = None)]
+struct Args {
+    /// The TOML config file to read.
+    #[clap(long, short, env = "RELAY_CONFIG")]
+    config:               Option<PathBuf>,
+    /// Serve `gRPC` and the runner websocket on this one address.
+    #[clap(long, env = "LISTEN_ADDRESS")]
+    listen_address:       Option<SocketAddr>,
+    /// The address the `gRPC` server listens on.
+    #[clap(long, env = "GRPC_ADDRESS")]
+    grpc_address:         Option<SocketAddr>,
+    /// The address the websocket server listens on.
+    #[clap(long, env = "WS_ADDRESS")]
+    ws_address:           Option<SocketAddr>,
+    /// The token required to make admin modifications to the server.
+    #[clap(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token:          Option<String>,
+    /// Where users are stored.
+    #[clap(long, env = "USER_STORE")]
+    user_store:           Option<StorageBackend>,
+    /// The JSON file used by the `file` user store.
+    #[clap(long, env = "USER_STORE_PATH")]
+    user_store_path:      Option<PathBuf>,
+    /// The URI of the `MongoDB` instance used by the `mongodb` user store.
+    #[clap(long, env = "MONGODB_URI", hide_env_values = true)]
+    mongodb_uri:          Option<String>,
+    /// The longest a command may run for, in seconds.
+    #[clap(long, env = "MAX_TASK_TIMEOUT")]
+    max_task_timeout:     Option<u64>,
+    /// How often runners are pinged, in seconds.
+    #[clap(long, env = "HEARTBEAT_INTERVAL")]
+    heartbeat_interval:   Option<u64>,
+    /// How many heartbeats in a row a runner may miss before it is
+    /// disconnected.
+    #[clap(long, env = "HEARTBEAT_MAX_MISSED")]
+    heartbeat_max_missed: Option<u32>,
+    /// The PEM-encoded certificate chain to serve over TLS.
+    #[clap(long, env = "TLS_CERT")]
+    tls_cert:             Option<PathBuf>,
+    /// The PEM-encoded private key of the TLS certificate.
+    #[clap(long, env = "TLS_KEY")]
+    tls_key:              Option<PathBuf>,
+    /// Which logs to output, as a `tracing` filter directive, e.g. `info`.
+    #[clap(long, env = "RUST_LOG")]
+    log_level:            Option<String>,
+}
+
+/// The server's configuration.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The token required to make admin modifications to the server.
+    pub(crate) admin_token: String,
+    pub(crate) listen:      ListenConfig,
+    pub(crate) storage:     StorageConfig,
+    pub(crate) limits:      LimitsConfig,
+    pub(crate) heartbeat:   HeartbeatConfig,
+    pub(crate) tls:         Option<TlsConfig>,
+    pub(crate) logging:     LoggingConfig,
+}
+
+/// The addresses the server listens on.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ListenConfig {
+    /// If set, `gRPC`, `gRPC`-web and the runner websocket (at `/runner`) are
+    /// all served on this address, and `grpc` and `websocket` are ignored.
+    pub(crate) address:   Option<SocketAddr>,
+    pub(crate) grpc:      SocketAddr,
+    pub(crate) websocket: SocketAddr,
+}
+
+/// A backend that users can be stored in.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StorageBackend {
+    #[default]
+    Mongodb,
+    /// Users are lost when the server stops.
+    Memory,
+    /// Users are kept in a JSON file.
+    File,
+}
+
+/// Where users are stored.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    pub(crate) backend:     StorageBackend,
+    /// Required by the `mongodb` backend.
+    pub(crate) mongodb_uri: Option<String>,
+    /// The JSON file used by the `file` backend.
+    pub(crate) path:        PathBuf,
+}
+
+/// Limits on what users can ask runners to do.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitsConfig {
+    /// The longest a command may run for, in seconds. Requested timeouts are
+    /// capped to this.
+    pub(crate) max_task_timeout: u64,
+}
+
+/// How the server checks that runners are still there.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HeartbeatConfig {
+    /// How often runners are pinged, in seconds.
+    pub(crate) interval:   u64,
+    /// How many heartbeats in a row a runner may miss before it is
+    /// disconnected.
+    pub(crate) max_missed: u32,
+}
+
+/// The certificate the server presents to clients and runners.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// The PEM-encoded certificate chain.
+    pub(crate) cert: PathBuf,
+    /// The PEM-encoded private key.
+    pub(crate) key:  PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LoggingConfig {
+    /// Which logs to output, as a `tracing` filter directive, e.g. `info` or
+    /// `server=debug,warn`.
+    pub(crate) level: String,
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ConfigError {
+    #[snafu(display("failed to read {}: {}", path.display(), source))]
+    Read {
+        path:   PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("failed to parse {}: {}", path.display(), source))]
+    Parse {
+        path:   PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("{}", message))]
+    Invalid { message: String },
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            address:   None,
+            grpc:      SocketAddr::from(([0, 0, 0, 0], 50051)),
+            websocket: SocketAddr::from(([0, 0, 0, 0], 50052)),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend:     StorageBackend::default(),
+            mongodb_uri: None,
+            path:        PathBuf::from("users.json"),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_task_timeout: 600,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval:   15,
+            max_missed: 3,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the config file, environment variables and
+    /// flags, in increasing order of precedence, and validates it.
+    pub(crate) fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply(args)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Reads the configuration from a TOML file. Missing settings are given
+    /// their defaults.
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).context(ReadSnafu { path })?;
+        toml::from_str(&contents).context(ParseSnafu { path })
+    }
+
+    /// Overrides settings with those given as environment variables or flags.
+    fn apply(&mut self, args: Args) -> Result<(), ConfigError> {
+        if let Some(token) = args.admin_token {
+            self.admin_token = token;
+        }
+        if let Some(addr) = args.listen_address {
+            self.listen.address = Some(addr);
+        }
+        if let Some(addr) = args.grpc_address {
+            self.listen.grpc = addr;
+        }
+        if let Some(addr) = args.ws_address {
+            self.listen.websocket = addr;
+        }
+        if let Some(backend) = args.user_store {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = args.user_store_path {
+            self.storage.path = path;
+        }
+        if let Some(uri) = args.mongodb_uri {
+            self.storage.mongodb_uri = Some(uri);
+        }
+        if let Some(timeout) = args.max_task_timeout {
+            self.limits.max_task_timeout = timeout;
+        }
+        if let Some(interval) = args.heartbeat_interval {
+            self.heartbeat.interval = interval;
+        }
+        if let Some(max_missed) = args.heartbeat_max_missed {
+            self.heartbeat.max_missed = max_missed;
+        }
+        if let Some(level) = args.log_level {
+            self.logging.level = level;
+        }
+
+        match (args.tls_cert, args.tls_key, &mut self.tls) {
+            (None, None, _) => {},
+            (cert, key, Some(tls)) => {
+                tls.cert = cert.unwrap_or(std::mem::take(&mut tls.cert));
+                tls.key = key.unwrap_or(std::mem::take(&mut tls.key));
+            },
+            (Some(cert), Some(key), None) => self.tls = Some(TlsConfig { cert, key }),
+            (_, _, None) => {
+                return InvalidSnafu {
+                    message: "--tls-cert and --tls-key must be given together",
+                }
+                .fail()
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the configuration is usable.
+    fn validate(&self) -> Result<(), ConfigError> {
+        ensure!(
+            !self.admin_token.is_empty(),
+            InvalidSnafu {
+                message: "an admin token must be set with `admin_token`, ADMIN_TOKEN or \
+                          --admin-token",
+            }
+        );
+        ensure!(
+            !matches!(self.storage.backend, StorageBackend::Mongodb)
+                || self.storage.mongodb_uri.is_some(),
+            InvalidSnafu {
+                message: "the mongodb user store requires `storage.mongodb_uri`, MONGODB_URI or \
+                          --mongodb-uri",
+            }
+        );
+        ensure!(
+            self.limits.max_task_timeout > 0,
+            InvalidSnafu {
+                message: "`limits.max_task_timeout` must be at least 1 second",
+            }
+        );
+        ensure!(
+            self.heartbeat.interval > 0 && self.heartbeat.max_missed > 0,
+            InvalidSnafu {
+                message: "`heartbeat.interval` and `heartbeat.max_missed` must be at least 1",
+            }
+        );
+        ensure!(
+            self.listen.address.is_some() || self.listen.grpc != self.listen.websocket,
+            InvalidSnafu {
+                message: format!(
+                    "the gRPC and websocket servers can't both listen on {}",
+                    self.listen.grpc
+                ),
+            }
+        );
+
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert, &tls.key] {
+                ensure!(
+                    path.is_file(),
+                    InvalidSnafu {
+                        message: format!("TLS file {} does not exist", path.display()),
+                    }
+                );
+            }
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.logging.level) {
+            return InvalidSnafu {
+                message: format!("invalid `logging.level` {:?}: {}", self.logging.level, e),
+            }
+            .fail();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        net::SocketAddr,
+        sync::{Mutex, MutexGuard, PoisonError},
+    };
+
+    use clap::{CommandFactory, Parser};
+
+    use super::{Args, Config, ConfigError, StorageBackend, TlsConfig};
+
+    /// Held by whichever test is using the environment.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Sets the environment variables `Args` reads for as long as it's held,
+    /// restoring them when dropped. Only one test can hold it at a time.
+    struct Env {
+        _lock: MutexGuard<'static, ()>,
+        saved: Vec<(OsString, Option<OsString>)>,
+    }
+
+    impl Env {
+        /// Sets `vars`, and unsets every other variable `Args` reads, so that
+        /// the environment the tests run in can't leak into them.
+        fn set(vars: &[(&str, &str)]) -> Self {
+            let lock = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            let saved = Args::command()
+                .get_arguments()
+                .filter_map(clap::Arg::get_env)
+                .map(|name| (name.to_os_string(), std::env::var_os(name)))
+                .collect::<Vec<_>>();
+
+            for (name, _) in &saved {
+                std::env::remove_var(name);
+            }
+            for (name, value) in vars {
+                std::env::set_var(name, value);
+            }
+
+            Self { _lock: lock, saved }
+        }
+    }
+
+    impl Drop for Env {
+        fn drop(&mut self) {
+            for (name, value) in self.saved.drain(..) {
+                match value {
+                    Some(value) => std::env::set_var(name, value),
+                    None => std::env::remove_var(name),
+                }
+            }
+        }
+    }
+
+    /// Reads `toml` as a config file, then applies `env` and `args` to it.
+    fn load(toml: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Config, ConfigError> {
+        let mut config: Config = toml::from_str(toml).unwrap();
+        let args = {
+            let _env = Env::set(env);
+            Args::try_parse_from([&["server"], args].concat()).unwrap()
+        };
+        config.apply(args)?;
+        Ok(config)
+    }
+
+    fn addr(addr: &str) -> SocketAddr { addr.parse().unwrap() }
+
+    /// A config that passes validation.
+    fn valid() -> Config {
+        let mut config = Config {
+            admin_token: "secret".to_string(),
+            ..Config::default()
+        };
+        config.storage.backend = StorageBackend::Memory;
+        config
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let config = load(
+            r#"
+            [listen]
+            grpc = "127.0.0.1:7000"
+            websocket = "127.0.0.1:7002"
+
+            [heartbeat]
+            interval = 30
+            max_missed = 4
+            "#,
+            &[
+                ("GRPC_ADDRESS", "127.0.0.1:7001"),
+                ("HEARTBEAT_INTERVAL", "5"),
+            ],
+            &["--grpc-address", "127.0.0.1:7003"],
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.grpc, addr("127.0.0.1:7003"));
+        assert_eq!(config.heartbeat.interval, 5);
+        assert_eq!(config.listen.websocket, addr("127.0.0.1:7002"));
+        assert_eq!(config.heartbeat.max_missed, 4);
+        // settings that are never given keep their defaults
+        assert_eq!(config.limits.max_task_timeout, 600);
+        assert!(config.listen.address.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        for invalid in [
+            r#"admin_tokn = "secret""#,
+            "[listen]\ngrcp = \"127.0.0.1:7000\"",
+            "[heartbeat]\ninterval = 5\ntimeout = 5",
+            "[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nca = \"ca.pem\"",
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn tls_flags_must_be_given_together_unless_the_file_has_both() {
+        assert!(matches!(
+            load("", &[], &["--tls-cert", "cert.pem"]),
+            Err(ConfigError::Invalid { .. })
+        ));
+
+        let config = load(
+            "[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"",
+            &[],
+            &["--tls-key", "other.pem"],
+        )
+        .unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert.to_str(), Some("cert.pem"));
+        assert_eq!(tls.key.to_str(), Some("other.pem"));
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert!(valid().validate().is_ok());
+
+        let invalid: [fn(&mut Config); 8] = [
+            |c| c.admin_token.clear(),
+            |c| c.storage.backend = StorageBackend::Mongodb,
+            |c| c.limits.max_task_timeout = 0,
+            |c| c.heartbeat.interval = 0,
+            |c| c.heartbeat.max_missed = 0,
+            |c| c.listen.websocket = c.listen.grpc,
+            |c| c.logging.level = "server=loud".to_string(),
+            |c| {
+                c.tls = Some(TlsConfig {
+                    cert: "/does/not/exist.pem".into(),
+                    key:  "/does/not/exist.key".into(),
+                });
+            },
+        ];
+        for (i, change) in invalid.into_iter().enumerate() {
+            let mut config = valid();
+            change(&mut config);
+            assert!(
+                matches!(config.validate(), Err(ConfigError::Invalid { .. })),
+                "{i}"
+            );
+        }
+
+        // a combined listener ignores the separate addresses
+        let mut config = valid();
+        config.listen.websocket = config.listen.grpc;
+        config.listen.address = Some(addr("127.0.0.1:7000"));
+        assert!(config.validate().is_ok());
+
+        let mut config = valid();
+        config.storage.backend = StorageBackend::Mongodb;
+        config.storage.mongodb_uri = Some("mongodb://localhost".to_string());
+        assert!(config.validate().is_ok());
+    }
+}
@@ -1,6 +1,10 @@
 use tonic::metadata::MetadataMap;
 
-use crate::USER_MANAGER;
+use crate::{
+    auth::tokens::{secrets_match, TokenScope},
+    CONFIG,
+    USER_MANAGER,
+};
 
 /// Gets the zid from the token from a `gRPC` request metadata map.
 pub(crate) async fn get_zid(meta: &MetadataMap) -> Option<String> {
@@ -13,16 +17,13 @@ pub(crate) async fn get_zid(meta: &MetadataMap) -> Option<String> {
     let token = auth_data.replace("Bearer ", "");
 
     let manager = USER_MANAGER.get().unwrap();
-    let user = manager.get_by_token(&token).await;
+    let user = manager.authenticate(&token, TokenScope::Client).await?;
 
-    match user {
-        Some(u) => Some(u.zid),
-        None => None,
-    }
+    Some(user.zid)
 }
 
 pub(crate) fn is_admin(meta: &MetadataMap) -> Option<bool> {
-    let admin_token = std::env::var("ADMIN_TOKEN").expect("ADMIN_TOKEN must be set");
+    let admin_token = &CONFIG.get()?.admin_token;
     let auth_data = meta.get("Authorization")?.to_str().ok()?;
 
     if !auth_data.starts_with("Bearer ") {
@@ -30,7 +31,7 @@ pub(crate) fn is_admin(meta: &MetadataMap) -> Option<bool> {
     }
 
     let token = auth_data.replace("Bearer ", "");
-    if admin_token != token {
+    if !secrets_match(admin_token, &token) {
         return None;
     }
 
@@ -8,7 +8,7 @@ macro_rules! generic_failed {
     ($msg:expr, $($arg:expr),*) => {{
         error!($msg, $($arg),*);
         Ok(Response::new(GenericResponse {
-            success: true,
+            success: false,
             error:   format!($msg, $($arg),*),
         }))
     }};
@@ -1,13 +1,47 @@
+use std::pin::Pin;
+
+use futures::{Stream, StreamExt};
+use snafu::Whatever;
 use tonic::{Request, Response, Status};
 use tracing::{debug, error, instrument};
 
 use self::interceptors::is_admin;
 use crate::{
-    auth::User,
-    client_manager::ClientManagerError,
+    auth::{
+        tokens::{TokenScope, UserToken},
+        User,
+        UserManager,
+    },
+    client_manager::{ClientManagerError, TaskEvent},
     relay::{
-        admin::{DeleteUserRequest, GenericResponse, UpsertUserRequest},
-        core::{relay_service_server::RelayService, CommandRequest, CommandResponse},
+        admin::{
+            DeleteUserRequest,
+            DisconnectRunnerRequest,
+            GenerateTokenRequest,
+            GenerateTokenResponse,
+            GenericResponse,
+            ListRunnersRequest,
+            ListRunnersResponse,
+            ListTasksRequest,
+            ListTasksResponse,
+            ListTokensRequest,
+            ListTokensResponse,
+            ListUsersRequest,
+            ListUsersResponse,
+            RevokeTokenRequest,
+            TokenInfo,
+            TokenScope as ProtoTokenScope,
+            UpsertUserRequest,
+            UserInfo,
+        },
+        core::{
+            command_output,
+            relay_service_server::RelayService,
+            CancelCommandRequest,
+            CommandOutput,
+            CommandRequest,
+            CommandResponse,
+        },
     },
     MANAGER,
     USER_MANAGER,
@@ -19,18 +53,97 @@ mod macros;
 #[derive(Debug, Default)]
 pub struct Relay {}
 
+/// The number of users returned by `ListUsers` if no page size is given.
+const DEFAULT_USER_PAGE_SIZE: usize = 100;
+
+/// The most users `ListUsers` returns at once.
+const MAX_USER_PAGE_SIZE: usize = 1000;
+
+/// Converts a task forwarding error into the `gRPC` status returned to the
+/// caller.
+fn forwarding_status(e: &ClientManagerError) -> Status {
+    error!("failed to forward task: {:?}", e);
+    match e {
+        ClientManagerError::NoRunner => Status::unavailable("NoRunner"),
+        ClientManagerError::TimedOut => Status::deadline_exceeded("TimedOut"),
+        ClientManagerError::NoTask => Status::not_found("NoTask"),
+        ClientManagerError::RunnerDisconnected => Status::unavailable("RunnerDisconnected"),
+        ClientManagerError::InvalidResponse => Status::internal("InvalidResponse"),
+    }
+}
+
+impl From<TokenScope> for ProtoTokenScope {
+    fn from(scope: TokenScope) -> Self {
+        match scope {
+            TokenScope::Client => Self::Client,
+            TokenScope::Runner => Self::Runner,
+            TokenScope::Any => Self::Any,
+        }
+    }
+}
+
+/// Converts the scope requested for a new token. New tokens must be scoped to
+/// either clients or runners, so an unset scope is rejected rather than
+/// defaulting to one of them.
+fn new_token_scope(scope: i32) -> Option<TokenScope> {
+    match ProtoTokenScope::from_i32(scope)? {
+        ProtoTokenScope::Client => Some(TokenScope::Client),
+        ProtoTokenScope::Runner => Some(TokenScope::Runner),
+        ProtoTokenScope::Unspecified | ProtoTokenScope::Any => None,
+    }
+}
+
+/// Gets the page of users after `page_token`, which is the zid of the last user
+/// on the previous page, along with the token of the next page. The next page
+/// token is empty once there are no users left.
+async fn user_page(
+    users: &UserManager,
+    page_token: &str,
+    page_size: u32,
+) -> Result<(Vec<User>, String), Whatever> {
+    let page_size = match usize::try_from(page_size).unwrap_or(MAX_USER_PAGE_SIZE) {
+        0 => DEFAULT_USER_PAGE_SIZE,
+        size => size.min(MAX_USER_PAGE_SIZE),
+    };
+    let after = (!page_token.is_empty()).then_some(page_token);
+
+    // fetch one more user than fits on the page, to know whether there's
+    // another page without returning an empty one
+    let mut users = users.list_users(after, page_size + 1).await?;
+    let next_page_token = if users.len() > page_size {
+        users.truncate(page_size);
+        users.last().map(|u| u.zid.clone()).unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    Ok((users, next_page_token))
+}
+
+impl From<&UserToken> for TokenInfo {
+    fn from(token: &UserToken) -> Self {
+        Self {
+            id:         token.id.clone(),
+            label:      token.label.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at.unwrap_or_default(),
+            scope:      ProtoTokenScope::from(token.scope).into(),
+        }
+    }
+}
+
 #[tonic::async_trait]
 impl RelayService for Relay {
+    type CommandStreamStream =
+        Pin<Box<dyn Stream<Item = Result<CommandOutput, Status>> + Send + 'static>>;
+
     #[instrument]
     async fn command(
         &self,
         request: Request<CommandRequest>,
     ) -> Result<Response<CommandResponse>, Status> {
-        let zid = match interceptors::get_zid(request.metadata()).await {
-            Some(z) => z,
-            None => {
-                return unauthenticated!("You must be authenticated to use this service.");
-            },
+        let Some(zid) = interceptors::get_zid(request.metadata()).await else {
+            return unauthenticated!("You must be authenticated to use this service.");
         };
 
         let mgr = MANAGER.get().unwrap();
@@ -39,12 +152,57 @@ impl RelayService for Relay {
 
         match result {
             Ok(v) => Ok(Response::new(v)),
-            Err(e) => {
-                error!("failed to forward task: {:?}", e);
-                match e {
-                    ClientManagerError::NoRunner => Err(Status::unavailable("NoRunner")),
-                }
-            },
+            Err(e) => Err(forwarding_status(&e)),
+        }
+    }
+
+    // `Status` is dictated by tonic
+    #[allow(clippy::result_large_err)]
+    #[instrument]
+    async fn command_stream(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<Self::CommandStreamStream>, Status> {
+        let Some(zid) = interceptors::get_zid(request.metadata()).await else {
+            return unauthenticated!("You must be authenticated to use this service.");
+        };
+
+        let mgr = MANAGER.get().unwrap();
+        let (id, events) = mgr
+            .stream_task(&zid, request.into_inner())
+            .await
+            .map_err(|e| forwarding_status(&e))?;
+
+        // identify the task first, then relay each chunk as it arrives, and finish
+        // with the result. the task is cancelled if the client goes away first
+        let id = futures::stream::once(futures::future::ready(Ok(CommandOutput {
+            data: Some(command_output::Data::TaskId(id)),
+        })));
+        let events = events.map(|event| {
+            let data = match event {
+                TaskEvent::Output(chunk) => command_output::Data::Chunk(chunk),
+                TaskEvent::Finished(Ok(response)) => command_output::Data::Result(response),
+                TaskEvent::Finished(Err(e)) => return Err(forwarding_status(&e)),
+            };
+            Ok(CommandOutput { data: Some(data) })
+        });
+
+        Ok(Response::new(Box::pin(id.chain(events))))
+    }
+
+    #[instrument]
+    async fn cancel_command(
+        &self,
+        request: Request<CancelCommandRequest>,
+    ) -> Result<Response<GenericResponse>, Status> {
+        let Some(zid) = interceptors::get_zid(request.metadata()).await else {
+            return unauthenticated!("You must be authenticated to use this service.");
+        };
+
+        let mgr = MANAGER.get().unwrap();
+        match mgr.cancel_task(&zid, &request.into_inner().task_id).await {
+            Ok(()) => generic_success!(),
+            Err(e) => Err(forwarding_status(&e)),
         }
     }
 
@@ -57,14 +215,8 @@ impl RelayService for Relay {
 
         let mgr = USER_MANAGER.get().unwrap();
         let req = request.into_inner();
-        match mgr
-            .upsert_user(User {
-                zid:   req.zid,
-                token: req.token,
-            })
-            .await
-        {
-            Ok(_) => generic_success!(),
+        match mgr.set_token(req.zid, &req.token).await {
+            Ok(()) => generic_success!(),
             Err(e) => generic_failed!("failed to upsert user: {:?}", e),
         }
     }
@@ -78,9 +230,216 @@ impl RelayService for Relay {
 
         let mgr = USER_MANAGER.get().unwrap();
         let req = request.into_inner();
-        match mgr.delete_by_zid(&req.zid).await {
-            Ok(_) => generic_success!(),
+        match mgr.delete_user(&req.zid).await {
+            Ok(()) => generic_success!(),
             Err(e) => generic_failed!("failed to delete user: {:?}", e),
         }
     }
+
+    #[instrument]
+    async fn generate_token(
+        &self,
+        request: Request<GenerateTokenRequest>,
+    ) -> Result<Response<GenerateTokenResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = USER_MANAGER.get().unwrap();
+        let req = request.into_inner();
+        let ttl_seconds = (req.ttl_seconds != 0).then_some(req.ttl_seconds);
+        let replaces = (!req.replaces.is_empty()).then_some(req.replaces.as_str());
+        let Some(scope) = new_token_scope(req.scope) else {
+            return Err(Status::invalid_argument(
+                "new tokens must be scoped to either CLIENT or RUNNER",
+            ));
+        };
+
+        match mgr
+            .generate_token(&req.zid, req.label, scope, ttl_seconds, replaces)
+            .await
+        {
+            Ok((token, info)) => Ok(Response::new(GenerateTokenResponse {
+                token,
+                info: Some(TokenInfo::from(&info)),
+            })),
+            Err(e) => {
+                error!("failed to generate token: {}", e);
+                Err(Status::failed_precondition(e.to_string()))
+            },
+        }
+    }
+
+    #[instrument]
+    async fn revoke_token(
+        &self,
+        request: Request<RevokeTokenRequest>,
+    ) -> Result<Response<GenericResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = USER_MANAGER.get().unwrap();
+        let req = request.into_inner();
+        match mgr.revoke_token(&req.zid, &req.token_id).await {
+            Ok(()) => generic_success!(),
+            Err(e) => generic_failed!("failed to revoke token: {}", e),
+        }
+    }
+
+    #[instrument]
+    async fn list_tokens(
+        &self,
+        request: Request<ListTokensRequest>,
+    ) -> Result<Response<ListTokensResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = USER_MANAGER.get().unwrap();
+        let Some(user) = mgr.get_by_zid(&request.into_inner().zid).await else {
+            return Err(Status::not_found("no such user"));
+        };
+
+        Ok(Response::new(ListTokensResponse {
+            tokens: user.tokens.iter().map(TokenInfo::from).collect(),
+        }))
+    }
+
+    #[instrument]
+    async fn list_users(
+        &self,
+        request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = USER_MANAGER.get().unwrap();
+        let req = request.into_inner();
+        let (users, next_page_token) = match user_page(mgr, &req.page_token, req.page_size).await {
+            Ok(page) => page,
+            Err(e) => {
+                error!("failed to list users: {}", e);
+                return Err(Status::internal(e.to_string()));
+            },
+        };
+
+        Ok(Response::new(ListUsersResponse {
+            users: users
+                .iter()
+                .map(|user| UserInfo {
+                    zid:    user.zid.clone(),
+                    tokens: user.tokens.iter().map(TokenInfo::from).collect(),
+                })
+                .collect(),
+            next_page_token,
+        }))
+    }
+
+    #[instrument]
+    async fn list_runners(
+        &self,
+        request: Request<ListRunnersRequest>,
+    ) -> Result<Response<ListRunnersResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = MANAGER.get().unwrap();
+        Ok(Response::new(ListRunnersResponse {
+            runners: mgr.list_runners().await,
+        }))
+    }
+
+    #[instrument]
+    async fn disconnect_runner(
+        &self,
+        request: Request<DisconnectRunnerRequest>,
+    ) -> Result<Response<GenericResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = MANAGER.get().unwrap();
+        match mgr.disconnect_runner(&request.into_inner().zid).await {
+            Ok(()) => generic_success!(),
+            Err(e) => generic_failed!("failed to disconnect runner: {}", e),
+        }
+    }
+
+    #[instrument]
+    async fn list_tasks(
+        &self,
+        request: Request<ListTasksRequest>,
+    ) -> Result<Response<ListTasksResponse>, Status> {
+        validate_admin!(request);
+
+        let mgr = MANAGER.get().unwrap();
+        Ok(Response::new(ListTasksResponse {
+            tasks: mgr.tasks.list().await,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_token_scope, user_page};
+    use crate::{
+        auth::{tests::manager, tokens::TokenScope, UserManager},
+        relay::admin::TokenScope as ProtoTokenScope,
+    };
+
+    #[test]
+    fn new_tokens_must_have_a_scope() {
+        assert_eq!(
+            new_token_scope(ProtoTokenScope::Client as i32),
+            Some(TokenScope::Client)
+        );
+        assert_eq!(
+            new_token_scope(ProtoTokenScope::Runner as i32),
+            Some(TokenScope::Runner)
+        );
+        for scope in [
+            ProtoTokenScope::Unspecified as i32,
+            ProtoTokenScope::Any as i32,
+            42,
+        ] {
+            assert_eq!(new_token_scope(scope), None);
+        }
+    }
+
+    /// Lists every user in `store`, `page_size` at a time, returning the zids
+    /// on each page.
+    async fn pages(users: &UserManager, page_size: u32) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut page_token = String::new();
+        loop {
+            let (users, next) = user_page(users, &page_token, page_size).await.unwrap();
+            pages.push(users.into_iter().map(|u| u.zid).collect());
+            if next.is_empty() {
+                return pages;
+            }
+            page_token = next;
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_through_users() {
+        let users = manager();
+        assert_eq!(pages(&users, 2).await, [Vec::<String>::new()]);
+
+        for zid in ["z5555555", "z1111111", "z3333333", "z2222222"] {
+            users.set_token(zid.to_string(), zid).await.unwrap();
+        }
+        // an exact number of pages has no empty page at the end
+        assert_eq!(
+            pages(&users, 2).await,
+            [["z1111111", "z2222222"], ["z3333333", "z5555555"]]
+        );
+
+        users
+            .set_token("z4444444".to_string(), "z4444444")
+            .await
+            .unwrap();
+        assert_eq!(
+            pages(&users, 2).await,
+            [
+                vec!["z1111111", "z2222222"],
+                vec!["z3333333", "z4444444"],
+                vec!["z5555555"]
+            ]
+        );
+
+        // a page size of 0 uses the default
+        assert_eq!(pages(&users, 0).await.len(), 1);
+    }
 }
@@ -1,42 +1,105 @@
 #![warn(clippy::pedantic)]
 
+use std::{sync::Arc, time::Duration};
+
 use auth::UserManager;
+use config::Config;
 use once_cell::sync::OnceCell;
-use startup::{launch_grpc_server, launch_ws_server};
+use startup::{launch_combined_server, launch_grpc_server, launch_ws_server};
+use tls::CertificateStore;
 use tracing::error;
+use tracing_subscriber::EnvFilter;
+use ws::heartbeat::Heartbeat;
 
 use crate::client_manager::ClientManager;
 
 mod auth;
 mod client_manager;
+mod config;
 mod grpc;
 mod startup;
+mod tls;
 mod ws;
 
 pub mod relay;
 
 static MANAGER: OnceCell<ClientManager> = OnceCell::new();
 static USER_MANAGER: OnceCell<UserManager> = OnceCell::new();
+static CONFIG: OnceCell<Config> = OnceCell::new();
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    // logging isn't set up until the config is loaded, so errors go to stderr
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {e}");
+            std::process::exit(1);
+        },
+    };
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.logging.level))
+        .init();
 
     // set global manager
-    MANAGER.set(ClientManager::new()).unwrap();
+    MANAGER
+        .set(ClientManager::new(
+            Duration::from_secs(config.limits.max_task_timeout),
+            Heartbeat {
+                interval:   Duration::from_secs(config.heartbeat.interval),
+                max_missed: config.heartbeat.max_missed,
+            },
+        ))
+        .unwrap();
 
     // set user manager
-    USER_MANAGER.set(UserManager::new().await).unwrap();
+    USER_MANAGER
+        .set(UserManager::new(&config.storage).await)
+        .unwrap();
+
+    // load the certificate, which is reloaded whenever the server receives a SIGHUP
+    let acceptor = config
+        .tls
+        .as_ref()
+        .map(|tls| match CertificateStore::load(tls) {
+            Ok(store) => {
+                let store = Arc::new(store);
+                tls::reload_on_sighup(store.clone());
+                store.acceptor()
+            },
+            Err(e) => {
+                error!("Failed to load the TLS certificate. {}", e);
+                panic!()
+            },
+        });
+
+    // the servers read the config as soon as requests arrive, so it must be set
+    // before they're launched
+    let config = set_config(config);
 
     // launch the servers
-    let ws_handle = launch_ws_server();
-    let rpc_handle = launch_grpc_server();
+    let result = if let Some(addr) = config.listen.address {
+        launch_combined_server(addr, acceptor).await
+    } else {
+        let ws_handle = launch_ws_server(config.listen.websocket, acceptor.clone());
+        let rpc_handle = launch_grpc_server(config.listen.grpc, acceptor);
+        tokio::try_join!(ws_handle, rpc_handle).map(|_| ())
+    };
 
-    match tokio::try_join!(ws_handle, rpc_handle) {
-        Ok(_) => Ok(()),
+    match result {
+        Ok(()) => Ok(()),
         Err(e) => {
             error!("[main] error: {}", e);
             panic!()
         },
     }
 }
+
+fn set_config(config: Config) -> &'static Config {
+    if CONFIG.set(config).is_err() {
+        unreachable!("the config is only set once");
+    }
+
+    CONFIG.get().unwrap()
+}
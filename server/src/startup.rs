@@ -1,35 +1,72 @@
+use std::net::SocketAddr;
+
+use futures::StreamExt;
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use tonic::transport::Server;
 use tracing::info;
 
-use crate::{grpc::Relay, relay::core::relay_service_server::RelayServiceServer, ws};
+use crate::{
+    grpc::Relay,
+    relay::core::relay_service_server::RelayServiceServer,
+    tls,
+    ws::{self, upgrade::RunnerUpgrade},
+};
 
-pub(crate) fn launch_grpc_server() -> tokio::task::JoinHandle<()> {
+pub(crate) fn launch_grpc_server(
+    grpc_addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        let grpc_addr = "0.0.0.0:50051".parse().expect("failed to parse address");
         let relay = Relay::default();
         let svc = RelayServiceServer::new(relay).accept_gzip().send_gzip();
-
-        info!("[gRPC] launching gRPC server on {}", grpc_addr);
-        Server::builder()
+        let router = Server::builder()
             .accept_http1(true)
-            .add_service(tonic_web::enable(svc))
-            .serve(grpc_addr)
+            .add_service(tonic_web::enable(svc));
+
+        let Some(acceptor) = tls else {
+            info!("[gRPC] launching gRPC server on {}", grpc_addr);
+            router.serve(grpc_addr).await.expect("failed to serve gRPC");
+            return;
+        };
+
+        let listener = TcpListener::bind(grpc_addr).await.expect("failed to bind");
+
+        info!("[gRPC] launching gRPC server on {} with TLS", grpc_addr);
+        router
+            .serve_with_incoming(tls::incoming(listener, acceptor))
             .await
             .expect("failed to serve gRPC");
     })
 }
 
-pub(crate) fn launch_ws_server() -> tokio::task::JoinHandle<()> {
+pub(crate) fn launch_ws_server(
+    ws_addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        let listener = TcpListener::bind("0.0.0.0:50052")
-            .await
-            .expect("failed to bind");
+        let listener = TcpListener::bind(ws_addr).await.expect("failed to bind");
+
+        let Some(acceptor) = tls else {
+            info!("[ws] listening on {}", ws_addr);
+
+            while let Ok((stream, _)) = listener.accept().await {
+                let peer = stream
+                    .peer_addr()
+                    .expect("connected streams should have a peer address");
+                // deal with the connection
+                tokio::spawn(ws::handle_connection(stream, peer));
+            }
+            return;
+        };
 
-        info!("[ws] listening on {}", "0.0.0.0:50052");
+        info!("[ws] listening on {} with TLS", ws_addr);
 
-        while let Ok((stream, _)) = listener.accept().await {
+        let mut incoming = tls::incoming(listener, acceptor);
+        while let Some(Ok(stream)) = incoming.next().await {
             let peer = stream
+                .get_ref()
+                .0
                 .peer_addr()
                 .expect("connected streams should have a peer address");
             // deal with the connection
@@ -37,3 +74,36 @@ pub(crate) fn launch_ws_server() -> tokio::task::JoinHandle<()> {
         }
     })
 }
+
+/// Serves `gRPC`, `gRPC`-web and the runner websocket on a single address.
+/// Runners connect by upgrading a request to [`ws::upgrade::RUNNER_PATH`].
+pub(crate) fn launch_combined_server(
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let relay = Relay::default();
+        let svc = RelayServiceServer::new(relay).accept_gzip().send_gzip();
+        let router = Server::builder()
+            .accept_http1(true)
+            .layer(tower::layer::layer_fn(RunnerUpgrade::new))
+            .add_service(tonic_web::enable(svc));
+
+        let Some(acceptor) = tls else {
+            info!("[gRPC] launching gRPC and websocket server on {}", addr);
+            router.serve(addr).await.expect("failed to serve gRPC");
+            return;
+        };
+
+        let listener = TcpListener::bind(addr).await.expect("failed to bind");
+
+        info!(
+            "[gRPC] launching gRPC and websocket server on {} with TLS",
+            addr
+        );
+        router
+            .serve_with_incoming(tls::incoming(listener, acceptor))
+            .await
+            .expect("failed to serve gRPC");
+    })
+}
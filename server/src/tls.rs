@@ -0,0 +1,362 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{self, CertifiedKey},
+        Certificate,
+        PrivateKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::config::TlsConfig;
+
+/// How long a client has to complete the TLS handshake before its connection
+/// is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Snafu)]
+pub(crate) enum TlsError {
+    #[snafu(display("failed to read {}: {}", path.display(), source))]
+    ReadPem {
+        path:   PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("{} contains no certificates", path.display()))]
+    NoCertificates { path: PathBuf },
+    #[snafu(display("{} contains no private key", path.display()))]
+    NoPrivateKey { path: PathBuf },
+    #[snafu(display("the private key in {} is not supported", path.display()))]
+    UnsupportedKey { path: PathBuf },
+}
+
+/// Serves the certificate most recently loaded from the configured files, so
+/// that it can be replaced without restarting the server.
+pub(crate) struct CertificateStore {
+    cert_path: PathBuf,
+    key_path:  PathBuf,
+    current:   RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateStore {
+    /// Loads the certificate and private key named in the config.
+    pub(crate) fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let current = load_certified_key(&config.cert, &config.key)?;
+        Ok(Self {
+            cert_path: config.cert.clone(),
+            key_path:  config.key.clone(),
+            current:   RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Reloads the certificate and private key from disk. The current
+    /// certificate is kept if they can't be loaded.
+    pub(crate) fn reload(&self) -> Result<(), TlsError> {
+        let reloaded = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(reloaded);
+        Ok(())
+    }
+
+    /// Creates an acceptor that serves whichever certificate is current when
+    /// each connection is made.
+    pub(crate) fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        // gRPC requires HTTP/2, while gRPC-web and websockets use HTTP/1.1
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reads a PEM-encoded certificate chain and private key.
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open_pem(cert_path)?)
+        .context(ReadPemSnafu { path: cert_path })?;
+    if certs.is_empty() {
+        return NoCertificatesSnafu { path: cert_path }.fail();
+    }
+
+    let key = rustls_pemfile::read_all(&mut open_pem(key_path)?)
+        .context(ReadPemSnafu { path: key_path })?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .context(NoPrivateKeySnafu { path: key_path })?;
+    let key = sign::any_supported_type(&key)
+        .ok()
+        .context(UnsupportedKeySnafu { path: key_path })?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, TlsError> {
+    let file = File::open(path).context(ReadPemSnafu { path })?;
+    Ok(BufReader::new(file))
+}
+
+/// Reloads the certificate whenever the server receives a `SIGHUP`.
+pub(crate) fn reload_on_sighup(store: Arc<CertificateStore>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!(
+                    "[tls] failed to listen for SIGHUP; certificates won't be reloaded: {}",
+                    e
+                );
+                return;
+            },
+        };
+
+        while hangups.recv().await.is_some() {
+            match store.reload() {
+                Ok(()) => info!("[tls] reloaded the certificate"),
+                Err(e) => error!(
+                    "[tls] failed to reload the certificate, keeping the old one: {}",
+                    e
+                ),
+            }
+        }
+    })
+}
+
+/// Accepts connections on `listener` and performs the TLS handshake for each.
+///
+/// Handshakes happen concurrently, so a slow client can't hold up the
+/// connections behind it. Connections whose handshake fails, or doesn't finish
+/// within [`HANDSHAKE_TIMEOUT`], are dropped.
+pub(crate) fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> UnboundedReceiver<Result<TlsStream<TcpStream>, std::io::Error>> {
+    let (tx, rx) = unbounded();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("[tls] failed to accept a connection: {}", e);
+                    continue;
+                },
+            };
+
+            // stop accepting once whoever was receiving connections has gone away
+            if tx.is_closed() {
+                return;
+            }
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let Some(stream) = handshake(&acceptor, stream, address).await else {
+                    return;
+                };
+
+                if tx.unbounded_send(Ok(stream)).is_err() {
+                    debug!("[tls] listener has shut down; dropping {}", address);
+                }
+            });
+        }
+    });
+
+    rx
+}
+
+#[instrument(skip(acceptor, stream))]
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+    address: SocketAddr,
+) -> Option<TlsStream<S>> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(e)) => {
+            debug!("[tls] handshake failed: {}", e);
+            None
+        },
+        Err(_) => {
+            debug!("[tls] handshake timed out");
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use rcgen::generate_simple_self_signed;
+    use tempfile::TempDir;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+    use tokio_rustls::{
+        rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    use super::{handshake, CertificateStore, HANDSHAKE_TIMEOUT};
+    use crate::config::TlsConfig;
+
+    /// A self-signed certificate for `localhost`.
+    struct TestCert {
+        der:      Vec<u8>,
+        cert_pem: String,
+        key_pem:  String,
+    }
+
+    fn generate_cert() -> TestCert {
+        let cert = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        TestCert {
+            der:      cert.serialize_der().unwrap(),
+            cert_pem: cert.serialize_pem().unwrap(),
+            key_pem:  cert.serialize_private_key_pem(),
+        }
+    }
+
+    fn write_cert(dir: &TempDir, cert: &TestCert) -> TlsConfig {
+        let config = TlsConfig {
+            cert: dir.path().join("cert.pem"),
+            key:  dir.path().join("key.pem"),
+        };
+        std::fs::write(&config.cert, &cert.cert_pem).unwrap();
+        std::fs::write(&config.key, &cert.key_pem).unwrap();
+        config
+    }
+
+    /// Connects to a server using `store`, trusting only `trusted`, and
+    /// returns whether the handshake succeeded and data made it across.
+    async fn connects(store: &Arc<CertificateStore>, trusted: &TestCert) -> bool {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(trusted.der.clone())).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client, server) = duplex(4096);
+        let acceptor = store.acceptor();
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.ok()?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.ok()?;
+            Some(buf)
+        });
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = async {
+            let mut stream = connector.connect(name, client).await.ok()?;
+            stream.write_all(b"ping").await.ok()?;
+            stream.flush().await.ok()?;
+            Some(stream)
+        }
+        .await;
+
+        // a failed handshake drops the client's end, so the server can't be left
+        // waiting; a successful one keeps it open until the server is done
+        let received = server.await.unwrap();
+        client.is_some() && received == Some(*b"ping")
+    }
+
+    #[tokio::test]
+    async fn serves_the_loaded_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = generate_cert();
+        let store = Arc::new(CertificateStore::load(&write_cert(&dir, &cert)).unwrap());
+
+        assert!(connects(&store, &cert).await);
+        assert!(!connects(&store, &generate_cert()).await);
+    }
+
+    #[tokio::test]
+    async fn reload_serves_the_new_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = generate_cert();
+        let store = Arc::new(CertificateStore::load(&write_cert(&dir, &old)).unwrap());
+
+        let new = generate_cert();
+        write_cert(&dir, &new);
+        store.reload().unwrap();
+
+        assert!(connects(&store, &new).await);
+        assert!(!connects(&store, &old).await);
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_the_old_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = generate_cert();
+        let config = write_cert(&dir, &cert);
+        let store = Arc::new(CertificateStore::load(&config).unwrap());
+
+        std::fs::write(&config.key, "not a key").unwrap();
+        assert!(store.reload().is_err());
+
+        assert!(connects(&store, &cert).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_stalled_handshakes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(CertificateStore::load(&write_cert(&dir, &generate_cert())).unwrap());
+
+        // the client connects, but never says anything
+        let (_client, server) = duplex(4096);
+        let started = Instant::now();
+        let address = SocketAddr::from(([127, 0, 0, 1], 50051));
+
+        assert!(handshake(&store.acceptor(), server, address)
+            .await
+            .is_none());
+        assert!(started.elapsed() >= HANDSHAKE_TIMEOUT);
+    }
+
+    #[test]
+    fn rejects_files_without_a_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_cert(&dir, &generate_cert());
+        std::fs::write(&config.cert, "").unwrap();
+
+        assert!(matches!(
+            CertificateStore::load(&config),
+            Err(super::TlsError::NoCertificates { .. })
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, instrument, warn};
+
+use super::models::Peer;
+
+/// How often peers are pinged, and how many pings in a row they may miss.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Heartbeat {
+    pub(crate) interval:   Duration,
+    pub(crate) max_missed: u32,
+}
+
+impl Heartbeat {
+    /// Pings the peer every interval, returning once nothing has been received
+    /// from it for `max_missed` intervals.
+    ///
+    /// Any message counts as a sign of life, so a busy peer is never dropped,
+    /// and an idle one keeps its connection alive by answering the pings.
+    #[instrument(skip(peer))]
+    pub(crate) async fn run(self, peer: Peer, address: SocketAddr) {
+        let mut ticks = tokio::time::interval(self.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick completes immediately
+        ticks.tick().await;
+
+        loop {
+            ticks.tick().await;
+
+            let silent_for = peer.last_seen().elapsed().unwrap_or_default();
+            if silent_for >= self.interval * self.max_missed {
+                warn!(
+                    "[ws] peer missed {} heartbeats; dropping connection: {}",
+                    self.max_missed, address
+                );
+                return;
+            }
+
+            if peer.send_message(Message::Ping(Vec::new())).is_err() {
+                debug!("[ws] connection closed; stopping heartbeat: {}", address);
+                return;
+            }
+        }
+    }
+}
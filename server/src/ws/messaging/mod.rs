@@ -22,6 +22,13 @@ pub(crate) async fn handle_message(msg: Message, address: SocketAddr) {
     let peer = peer_map
         .get(&address)
         .expect("peer was not in the peer map");
+    peer.touch();
+
+    // pings and pongs only keep the connection alive; tungstenite answers pings
+    // itself
+    if let Message::Ping(_) | Message::Pong(_) = msg {
+        return;
+    }
 
     if let Message::Binary(binary) = msg {
         // determine if the peer has been registered
@@ -62,9 +69,9 @@ pub(crate) async fn handle_message(msg: Message, address: SocketAddr) {
                     // so we will close it because sus
                     peer.close_with_policy();
                 },
-                Data::TaskRequest(_) => {
-                    // runners should not be sending task requests to the server
-                    warn!("[ws] runner sent task request");
+                Data::TaskRequest(_) | Data::TaskCancel(_) | Data::InitAck(_) => {
+                    // these frames only ever go from the server to runners
+                    warn!("[ws] runner sent a server-only frame");
                     peer.close_with_policy();
                 },
                 Data::TaskResponse(response) => {
@@ -73,6 +80,11 @@ pub(crate) async fn handle_message(msg: Message, address: SocketAddr) {
                     let manager = MANAGER.get().unwrap();
                     manager.tasks.complete_task(response).await;
                 },
+                Data::TaskOutput(output) => {
+                    // pass the output along to whoever is waiting on the task
+                    let manager = MANAGER.get().unwrap();
+                    manager.tasks.forward_output(output).await;
+                },
             }
         }
     } else {
@@ -1,32 +1,94 @@
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tracing::{instrument, warn};
 
-use crate::{relay::ws_extensions::InitFrame, ws::models::Peer, USER_MANAGER};
+use crate::{
+    auth::tokens::TokenScope,
+    relay::ws_extensions::{socket_frame::Data, InitAck, InitFrame, SocketFrame},
+    ws::{
+        models::Peer,
+        protocol::{self, Compatibility, PROTOCOL_VERSION},
+    },
+    USER_MANAGER,
+};
+
+/// The optional protocol features this server supports.
+const FEATURES: [&str; 3] = ["heartbeat", "output-streaming", "task-cancel"];
 
 /// Handle a registration message from a peer.
-#[instrument]
+#[instrument(skip(message))]
 pub(crate) async fn handle_registration(peer: &mut Peer, message: InitFrame) {
     // get the zid and token of the peer
     let zid = message.zid;
     let token = message.token;
 
+    // make sure the runner speaks a version of the protocol we can serve
+    let warning = match protocol::check(message.protocol_version) {
+        Compatibility::Supported => String::new(),
+        Compatibility::Deprecated { warning } => {
+            warn!("[ws] {} ({}, version {:?})", warning, zid, message.version);
+            warning
+        },
+        Compatibility::Unsupported { reason } => {
+            warn!("[ws] {} ({}, version {:?})", reason, zid, message.version);
+            reject(peer, &reason);
+            return;
+        },
+    };
+
     // check if this is a valid combo of zid and token
-    let user = USER_MANAGER.get().unwrap().get_by_zid(&zid).await;
-    match user {
-        Some(user) => {
-            // check if the token matches
-            if user.token == token {
-                // if the token matches, register the peer
-                peer.register(zid);
-            } else {
-                // if the token does not match, close the connection
-                warn!("[ws] invalid token");
-                peer.close_with_policy();
-            }
+    let user = USER_MANAGER
+        .get()
+        .unwrap()
+        .verify(&zid, &token, TokenScope::Runner)
+        .await;
+    if user.is_none() {
+        // the user does not exist or the token does not match, so we will reject
+        warn!("[ws] invalid zid or token");
+        reject(peer, "invalid zid or token");
+        return;
+    }
+
+    // if the token matches, register the peer
+    let features = FEATURES
+        .iter()
+        .filter(|f| message.features.iter().any(|r| r == *f))
+        .map(ToString::to_string)
+        .collect();
+    peer.register(zid.clone(), message.version);
+    acknowledge(
+        peer,
+        InitAck {
+            success: true,
+            zid,
+            features,
+            warning,
+            ..InitAck::default()
         },
-        None => {
-            // the user does not exist, so we will reject
-            warn!("[ws] user does not exist");
-            peer.close_with_policy();
+    );
+}
+
+/// Tells the peer why it was rejected, and closes its connection.
+fn reject(peer: &Peer, error: &str) {
+    acknowledge(
+        peer,
+        InitAck {
+            success: false,
+            error: error.to_string(),
+            ..InitAck::default()
         },
+    );
+    peer.close(CloseCode::Policy, error);
+}
+
+fn acknowledge(peer: &Peer, ack: InitAck) {
+    let frame = SocketFrame {
+        data: Some(Data::InitAck(InitAck {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            ..ack
+        })),
+    };
+    if let Err(e) = peer.send_socket_frame(&frame) {
+        warn!("[ws] failed to acknowledge registration: {}", e);
     }
 }
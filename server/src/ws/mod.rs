@@ -7,33 +7,57 @@ use futures::{
     StreamExt,
     TryStreamExt,
 };
-use tokio::{net::TcpStream, sync::RwLock};
-use tokio_tungstenite::tungstenite::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::RwLock,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 use tracing::{debug, info, instrument, warn};
 
 use self::models::Peer;
 use crate::MANAGER;
 
+pub(crate) mod heartbeat;
 mod messaging;
 pub(crate) mod models;
+mod protocol;
+pub(crate) mod upgrade;
 
 pub(crate) type TransmissionChannel = UnboundedSender<Message>;
 pub(crate) type PeerMap = Arc<RwLock<HashMap<SocketAddr, Peer>>>;
 
 #[instrument(skip(stream))]
-pub(crate) async fn handle_connection(stream: TcpStream, address: SocketAddr) {
+pub(crate) async fn handle_connection<S>(stream: S, address: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     info!("[ws] new connection from peer: {}", address);
 
     // perform websocket handshake
-    let ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("failed to accept websocket stream");
+    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!("[ws] failed to accept websocket stream: {}", e);
+            return;
+        },
+    };
+
+    handle_socket(ws_stream, address).await;
+}
+
+/// Relays messages to and from a peer over an established websocket.
+#[instrument(skip(ws_stream))]
+pub(crate) async fn handle_socket<S>(ws_stream: WebSocketStream<S>, address: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     info!("[ws] websocket connection established: {}", address);
 
     // register peer
-    let peer_map = MANAGER.get().unwrap().peers.clone();
+    let manager = MANAGER.get().unwrap();
     let (tx, rx) = unbounded();
-    peer_map.write().await.insert(address, Peer::new(tx));
+    let peer = Peer::new(tx);
+    manager.peers.write().await.insert(address, peer.clone());
 
     // channel to send messages to and channel to receive messages from
     let (outgoing, incoming) = ws_stream.split();
@@ -44,21 +68,26 @@ pub(crate) async fn handle_connection(stream: TcpStream, address: SocketAddr) {
 
         // if this is a close message, we will not process it
         if let Message::Close(_) = msg {
-            return future::ready(Ok(()));
+            return future::Either::Left(future::ok(()));
         }
 
-        tokio::spawn(async move {
+        // messages are handled in the order they arrive, so that task output
+        // is forwarded in order and always before the task's result
+        future::Either::Right(async move {
             messaging::handle_message(msg, address).await;
-        });
-
-        future::ok(())
+            Ok(())
+        })
     });
 
     // message -> tx:->rx -> outgoing
 
     let receive_from_others = rx.map(Ok).forward(outgoing);
 
-    pin_mut!(broadcast_incoming, receive_from_others);
+    // drop peers that have gone quiet, e.g. because their connection died
+    // without being closed
+    let heartbeat = manager.heartbeat.run(peer, address);
+
+    pin_mut!(broadcast_incoming, receive_from_others, heartbeat);
 
     // handle registration timeout
     tokio::spawn(async move {
@@ -80,9 +109,15 @@ pub(crate) async fn handle_connection(stream: TcpStream, address: SocketAddr) {
         }
     });
 
-    future::select(broadcast_incoming, receive_from_others).await;
+    future::select(
+        future::select(broadcast_incoming, receive_from_others),
+        heartbeat,
+    )
+    .await;
 
     info!("[ws] connection closed: {}", address);
-    let peer_map = MANAGER.get().unwrap().peers.clone();
-    peer_map.write().await.remove(&address);
+    manager.peers.write().await.remove(&address);
+
+    // nothing else can be sent to this runner, so fail anything it was running
+    manager.tasks.fail_runner_tasks(address).await;
 }
@@ -1,8 +1,13 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
 use tokio_tungstenite::tungstenite::{
     protocol::{frame::coding::CloseCode, CloseFrame},
     Message,
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use super::TransmissionChannel;
 use crate::relay::ws_extensions::SocketFrame;
@@ -10,23 +15,39 @@ use crate::relay::ws_extensions::SocketFrame;
 /// A websocket peer.
 #[derive(Debug, Clone)]
 pub(crate) struct Peer {
-    pub(crate) channel: TransmissionChannel,
-    pub(crate) data:    Option<PeerData>,
+    pub(crate) channel:      TransmissionChannel,
+    pub(crate) data:         Option<PeerData>,
+    /// When the peer connected.
+    pub(crate) connected_at: SystemTime,
+    /// When a message was last received from the peer.
+    last_seen:               Arc<Mutex<SystemTime>>,
 }
 
 impl Peer {
     pub(crate) fn new(tx: TransmissionChannel) -> Self {
+        let now = SystemTime::now();
         Self {
-            channel: tx,
-            data:    None,
+            channel:      tx,
+            data:         None,
+            connected_at: now,
+            last_seen:    Arc::new(Mutex::new(now)),
         }
     }
 
-    pub(crate) fn register(&mut self, zid: String) {
-        info!("[ws] registering peer: {}", zid);
-        self.data = Some(PeerData { username: zid });
+    pub(crate) fn register(&mut self, zid: String, version: String) {
+        info!("[ws] registering peer: {} (version {:?})", zid, version);
+        self.data = Some(PeerData {
+            username: zid,
+            version,
+        });
     }
 
+    /// Records that a message was just received from the peer.
+    pub(crate) fn touch(&self) { *self.last_seen.lock().unwrap() = SystemTime::now(); }
+
+    /// When a message was last received from the peer.
+    pub(crate) fn last_seen(&self) -> SystemTime { *self.last_seen.lock().unwrap() }
+
     /// Send's a message to the peer.
     pub(crate) fn send_message(
         &self,
@@ -35,20 +56,29 @@ impl Peer {
         self.channel.unbounded_send(message)
     }
 
-    pub(crate) fn send_socket_frame(&self, frame: &SocketFrame) {
+    pub(crate) fn send_socket_frame(
+        &self,
+        frame: &SocketFrame,
+    ) -> Result<(), futures::channel::mpsc::TrySendError<Message>> {
         let encoded = prost::Message::encode_to_vec(frame);
         let message = Message::Binary(encoded);
-        self.send_message(message).unwrap();
+        self.send_message(message)
     }
 
     /// Closes the peer's connection, with close code `Policy`.
-    pub(crate) fn close_with_policy(&self) {
-        self.send_message(Message::Close(Some(CloseFrame {
-            code:   CloseCode::Policy,
-            reason: "".into(),
-        })))
-        .unwrap();
-        info!("[ws] closing peer connecting with close code `Policy`");
+    pub(crate) fn close_with_policy(&self) { self.close(CloseCode::Policy, ""); }
+
+    /// Closes the peer's connection.
+    pub(crate) fn close(&self, code: CloseCode, reason: &str) {
+        if let Err(e) = self.send_message(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        }))) {
+            // the connection is already closing
+            warn!("[ws] failed to close peer connection: {}", e);
+            return;
+        }
+        info!("[ws] closing peer connecting with close code `{}`", code);
     }
 }
 
@@ -56,4 +86,6 @@ impl Peer {
 #[derive(Debug, Clone)]
 pub(crate) struct PeerData {
     pub(crate) username: String,
+    /// The runner's version, as it reported when registering.
+    pub(crate) version:  String,
 }
@@ -0,0 +1,78 @@
+/// The version of the websocket protocol this server speaks. It's bumped
+/// whenever `SocketFrame` changes in a way runners need to know about.
+pub(crate) const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest protocol version runners may connect with.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Runners older than this are still served, but told to upgrade.
+const DEPRECATED_BELOW: u32 = 2;
+
+/// Whether the server can serve a runner speaking a given protocol version.
+pub(crate) enum Compatibility {
+    Supported,
+    /// The runner is served, but should be upgraded.
+    Deprecated {
+        warning: String,
+    },
+    Unsupported {
+        reason: String,
+    },
+}
+
+/// Checks a runner's protocol version against the range this server supports.
+pub(crate) fn check(version: u32) -> Compatibility {
+    // runners from before versioning don't send one, but speak version 1
+    let version = version.max(1);
+
+    if version > PROTOCOL_VERSION {
+        Compatibility::Unsupported {
+            reason: format!(
+                "runner protocol version {version} is newer than this relay supports \
+                 ({PROTOCOL_VERSION})"
+            ),
+        }
+    } else if version < MIN_PROTOCOL_VERSION {
+        // unreachable while version 1 is supported, since unversioned runners
+        // are treated as version 1; this takes over once support for it is
+        // dropped by raising `MIN_PROTOCOL_VERSION`
+        Compatibility::Unsupported {
+            reason: format!(
+                "runner protocol version {version} is no longer supported; please upgrade the \
+                 runner"
+            ),
+        }
+    } else if version < DEPRECATED_BELOW {
+        Compatibility::Deprecated {
+            warning: format!(
+                "runner protocol version {version} is deprecated; please upgrade the runner"
+            ),
+        }
+    } else {
+        Compatibility::Supported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Compatibility, PROTOCOL_VERSION};
+
+    #[test]
+    fn unversioned_runners_speak_version_1() {
+        assert!(matches!(check(0), Compatibility::Deprecated { .. }));
+        assert!(matches!(check(1), Compatibility::Deprecated { .. }));
+    }
+
+    #[test]
+    fn supports_the_current_version() {
+        assert!(matches!(check(PROTOCOL_VERSION), Compatibility::Supported));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let Compatibility::Unsupported { reason } = check(PROTOCOL_VERSION + 1) else {
+            panic!("a newer version was accepted");
+        };
+        assert!(reason.contains("newer than this relay supports"));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use hyper::{
+    header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE},
+    Body,
+    Request,
+    Response,
+    StatusCode,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+    WebSocketStream,
+};
+use tonic::{
+    body::{empty_body, BoxBody},
+    transport::server::{TcpConnectInfo, TlsConnectInfo},
+};
+use tower::Service;
+use tracing::{info, instrument, warn};
+
+/// The path runners connect to when the websocket is served on the same port
+/// as `gRPC`.
+pub(crate) const RUNNER_PATH: &str = "/runner";
+
+/// Wraps the `gRPC` service, taking over requests to upgrade [`RUNNER_PATH`]
+/// to a websocket and handing the socket to the same logic as the standalone
+/// websocket server.
+#[derive(Debug, Clone)]
+pub(crate) struct RunnerUpgrade<S> {
+    inner: S,
+}
+
+impl<S> RunnerUpgrade<S> {
+    pub(crate) fn new(inner: S) -> Self { Self { inner } }
+}
+
+impl<S> Service<Request<Body>> for RunnerUpgrade<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Response = Response<BoxBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        if request.uri().path() != RUNNER_PATH {
+            return Box::pin(self.inner.call(request));
+        }
+
+        let response = upgrade(request);
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+/// Accepts a request to upgrade to a websocket, and handles the socket once
+/// the upgrade completes.
+#[instrument(skip(request))]
+fn upgrade(request: Request<Body>) -> Response<BoxBody> {
+    let Some(address) = remote_address(&request) else {
+        warn!("[ws] upgrade request has no remote address");
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let headers = request.headers();
+    let has_token = |name, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    let is_upgrade = has_token(CONNECTION, "upgrade")
+        && has_token(UPGRADE, "websocket")
+        && has_token(SEC_WEBSOCKET_VERSION, "13");
+    let Some(key) = headers.get(SEC_WEBSOCKET_KEY).filter(|_| is_upgrade) else {
+        warn!("[ws] invalid websocket upgrade request from {}", address);
+        return status(StatusCode::BAD_REQUEST);
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(request).await {
+            Ok(upgraded) => {
+                info!("[ws] new connection from peer: {}", address);
+                let ws_stream =
+                    WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                super::handle_socket(ws_stream, address).await;
+            },
+            Err(e) => warn!("[ws] failed to upgrade connection from {}: {}", address, e),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(empty_body())
+        .expect("the upgrade response should be valid")
+}
+
+/// Gets the address of whoever made the request, as recorded by tonic.
+fn remote_address(request: &Request<Body>) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    extensions
+        .get::<TcpConnectInfo>()
+        .or_else(|| {
+            extensions
+                .get::<TlsConnectInfo<TcpConnectInfo>>()
+                .map(TlsConnectInfo::get_ref)
+        })?
+        .remote_addr()
+}
+
+fn status(status: StatusCode) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(empty_body())
+        .expect("the response should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        task::{Context, Poll},
+    };
+
+    use futures::future::{ready, Ready};
+    use hyper::{
+        header::{
+            HeaderName,
+            CONNECTION,
+            SEC_WEBSOCKET_ACCEPT,
+            SEC_WEBSOCKET_KEY,
+            SEC_WEBSOCKET_VERSION,
+            UPGRADE,
+        },
+        Body,
+        Request,
+        Response,
+        StatusCode,
+    };
+    use tokio::net::{TcpListener, TcpStream};
+    use tonic::{body::BoxBody, transport::server::Connected};
+    use tower::Service;
+
+    use super::{status, RunnerUpgrade};
+
+    /// Stands in for the `gRPC` service, answering every request with 418.
+    #[derive(Clone)]
+    struct Teapot;
+
+    impl Service<Request<Body>> for Teapot {
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+        type Response = Response<BoxBody>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<Body>) -> Self::Future {
+            ready(Ok(status(StatusCode::IM_A_TEAPOT)))
+        }
+    }
+
+    /// The headers of a valid upgrade request, using the key from RFC 6455.
+    const UPGRADE_HEADERS: [(HeaderName, &str); 4] = [
+        (CONNECTION, "keep-alive, Upgrade"),
+        (UPGRADE, "websocket"),
+        (SEC_WEBSOCKET_VERSION, "13"),
+        (SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="),
+    ];
+
+    /// Sends a request for `path` with `headers` through the layer, from a
+    /// real connection so that it has a remote address.
+    async fn respond(path: &str, headers: &[(HeaderName, &str)]) -> Response<BoxBody> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut request = Request::builder().uri(path);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request.extensions_mut().insert(stream.connect_info());
+
+        RunnerUpgrade::new(Teapot).call(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_upgrades_to_the_runner_path() {
+        let response = respond("/runner", &UPGRADE_HEADERS).await;
+
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()[UPGRADE], "websocket");
+        assert_eq!(
+            response.headers()[SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_upgrades_to_the_runner_path() {
+        for missing in [
+            CONNECTION,
+            UPGRADE,
+            SEC_WEBSOCKET_VERSION,
+            SEC_WEBSOCKET_KEY,
+        ] {
+            let headers = UPGRADE_HEADERS
+                .iter()
+                .filter(|(name, _)| *name != missing)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                respond("/runner", &headers).await.status(),
+                StatusCode::BAD_REQUEST,
+                "{missing}"
+            );
+        }
+
+        let mut headers = UPGRADE_HEADERS.to_vec();
+        headers[0] = (CONNECTION, "keep-alive");
+        assert_eq!(
+            respond("/runner", &headers).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_other_paths_to_the_inner_service() {
+        for path in ["/", "/runner/", "/core.RelayService/Command"] {
+            assert_eq!(
+                respond(path, &UPGRADE_HEADERS).await.status(),
+                StatusCode::IM_A_TEAPOT,
+                "{path}"
+            );
+        }
+        assert_eq!(
+            respond("/core.RelayService/ListUsers", &[]).await.status(),
+            StatusCode::IM_A_TEAPOT
+        );
+    }
+}